@@ -0,0 +1,50 @@
+//! Compare the cost of verifying an access token when the public key is parsed for every request,
+//! as `decode_token` used to do, with verifying it against the key cached in `KeyStore`.
+//!
+//! cargo bench --bench jwt_keys
+
+use base64::{engine::general_purpose, Engine as _};
+use criterion::{criterion_group, criterion_main, Criterion};
+use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use serde_json::{json, Value};
+
+const PRIVATE_KEY: &str = include_str!("../tests/fixtures/access_token_private_key.pem");
+const PUBLIC_KEY: &str = include_str!("../tests/fixtures/access_token_public_key.pem");
+
+fn bench_decode_token(c: &mut Criterion) {
+    // the keys are configured as base64 encoded PEM
+    let public_key = general_purpose::STANDARD.encode(PUBLIC_KEY);
+    let claims = json!({
+        "token_id": "01HSJARKXDAH23Z8SF6ZY475TV",
+        "iss": "pwr.ink",
+        "sub": "Elton Zheng",
+        "iat": 1711085997,
+        "exp": 4102444800u64,
+    });
+    let token = encode(
+        &Header::new(Algorithm::RS256),
+        &claims,
+        &EncodingKey::from_rsa_pem(PRIVATE_KEY.as_bytes()).unwrap(),
+    )
+    .unwrap();
+    let validation = Validation::new(Algorithm::RS256);
+
+    let mut group = c.benchmark_group("decode_token");
+    group.bench_function("parse_key_per_request", |b| {
+        b.iter(|| {
+            let pem = general_purpose::STANDARD.decode(&public_key).unwrap();
+            let key = DecodingKey::from_rsa_pem(&pem).unwrap();
+            decode::<Value>(&token, &key, &validation).unwrap()
+        })
+    });
+
+    let key =
+        DecodingKey::from_rsa_pem(&general_purpose::STANDARD.decode(&public_key).unwrap()).unwrap();
+    group.bench_function("cached_key", |b| {
+        b.iter(|| decode::<Value>(&token, &key, &validation).unwrap())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_decode_token);
+criterion_main!(benches);
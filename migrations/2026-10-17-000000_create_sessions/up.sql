@@ -0,0 +1,21 @@
+-- the sessions of the postgres token store, used when `TOKEN_STORE` is postgres
+CREATE TABLE "sessions" (
+  id VARCHAR(26) PRIMARY KEY,
+  user_id INT4 NOT NULL REFERENCES "users" (id) ON DELETE CASCADE,
+  access_token_id VARCHAR(26) NOT NULL,
+  access_expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+  refresh_token_id VARCHAR(26) NOT NULL,
+  -- the session expires with its refresh token
+  expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+  created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  last_used_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  ip VARCHAR(64),
+  user_agent TEXT
+);
+CREATE INDEX "sessions_user_id_index" ON "sessions" (user_id);
+
+-- the refresh token ids already rotated in a session, to detect their reuse
+CREATE TABLE "rotated_tokens" (
+  token_id VARCHAR(26) PRIMARY KEY,
+  session_id VARCHAR(26) NOT NULL REFERENCES "sessions" (id) ON DELETE CASCADE
+);
@@ -0,0 +1,21 @@
+-- the TOTP secret of a user, the second factor is required once it's enabled
+CREATE TABLE "user_mfa" (
+  user_id INT4 PRIMARY KEY REFERENCES "users" (id) ON DELETE CASCADE,
+  -- base32 encoded
+  secret VARCHAR(64) NOT NULL,
+  -- NULL until the enrollment is confirmed with a code
+  enabled_at TIMESTAMP WITH TIME ZONE,
+  -- the time step of the last accepted code, a code can't be used twice
+  last_used_step INT8,
+  created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- the one-time recovery codes of a user, replacing a TOTP code once
+CREATE TABLE "mfa_recovery_codes" (
+  id SERIAL PRIMARY KEY,
+  user_id INT4 NOT NULL REFERENCES "users" (id) ON DELETE CASCADE,
+  -- sha256 hex digest of the code
+  code_hash VARCHAR(64) NOT NULL,
+  used_at TIMESTAMP WITH TIME ZONE
+);
+CREATE INDEX "mfa_recovery_codes_user_id_index" ON "mfa_recovery_codes" (user_id);
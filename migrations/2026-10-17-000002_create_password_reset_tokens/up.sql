@@ -0,0 +1,9 @@
+-- the password reset tokens sent by email, a token is deleted once it's used
+CREATE TABLE "password_reset_tokens" (
+  -- sha256 hex digest of the token
+  token_hash VARCHAR(64) PRIMARY KEY,
+  user_id INT4 NOT NULL REFERENCES "users" (id) ON DELETE CASCADE,
+  expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+  created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+CREATE INDEX "password_reset_tokens_user_id_index" ON "password_reset_tokens" (user_id);
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE IF EXISTS "email_verification_tokens";
+ALTER TABLE "users" DROP COLUMN IF EXISTS email_verified_at;
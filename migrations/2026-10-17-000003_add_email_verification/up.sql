@@ -0,0 +1,15 @@
+-- when the user proved to own their email, the accounts created before count as verified
+ALTER TABLE "users" ADD COLUMN email_verified_at TIMESTAMP WITH TIME ZONE;
+UPDATE "users" SET email_verified_at = COALESCE(created_at, CURRENT_TIMESTAMP);
+
+-- the email verification tokens sent by email, a token is deleted once it's used.
+-- The email it was sent to is kept, so the token doesn't verify an email changed in the meantime
+CREATE TABLE "email_verification_tokens" (
+  -- sha256 hex digest of the token
+  token_hash VARCHAR(64) PRIMARY KEY,
+  user_id INT4 NOT NULL REFERENCES "users" (id) ON DELETE CASCADE,
+  email VARCHAR NOT NULL,
+  expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+  created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+CREATE INDEX "email_verification_tokens_user_id_index" ON "email_verification_tokens" (user_id);
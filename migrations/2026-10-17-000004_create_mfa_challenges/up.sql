@@ -0,0 +1,11 @@
+-- the mfa challenge tokens exchanged at `/auth/mfa`, recorded by their id on their first use.
+-- A challenge is spent by a valid code, and rejected after a few wrong ones
+CREATE TABLE "mfa_challenges" (
+  jti VARCHAR(26) PRIMARY KEY,
+  user_id INT4 NOT NULL REFERENCES "users" (id) ON DELETE CASCADE,
+  failed_attempts INT4 NOT NULL DEFAULT 0,
+  used_at TIMESTAMP WITH TIME ZONE,
+  -- the expiration of the token, the challenge can be removed after it
+  expires_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
+CREATE INDEX "mfa_challenges_user_id_index" ON "mfa_challenges" (user_id);
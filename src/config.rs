@@ -0,0 +1,650 @@
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use derive_more::Display;
+use jsonwebtoken::Algorithm;
+
+// the config file used when `CONFIG_FILE` isn't set, it's optional
+const DEFAULT_CONFIG_FILE: &str = "config.toml";
+
+// the rate limits used when `RATE_LIMITS` isn't set
+const DEFAULT_RATE_LIMITS: &str =
+    "/api/v1/auth/register=10/3600, /api/v1/auth/password/forgot=5/3600, \
+    /api/v1/auth/verify-email/resend=5/3600, /api/v1/users/search=30/60, /api/v1/users=120/60";
+
+/// Application configuration, loaded once at startup and shared through `AppState`.
+///
+/// Every setting is read from the environment variable of its name (e.g. `DATABASE_URL`),
+/// or from the lowercase key in the TOML file (e.g. `database_url`) if the variable isn't set.
+/// The TOML file is `config.toml` in the working directory, or the path in `CONFIG_FILE`.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub server_port: u16,
+    pub database_url: String,
+    // where the sessions are kept, `REDIS_URL` is only required by the redis store
+    pub token_store: TokenStoreKind,
+    pub redis_url: String,
+    // token max ages are in minutes
+    pub access_token_max_age: u64,
+    pub refresh_token_max_age: u64,
+    // when the token store is unavailable, accept access tokens by their signature
+    // if they were issued at most `stateless_fallback_max_age` minutes ago
+    pub stateless_fallback: bool,
+    pub stateless_fallback_max_age: u64,
+    // the `iss` and `aud` claims of the issued tokens, checked when they are decoded
+    pub jwt_issuer: String,
+    pub jwt_audience: String,
+    // the signing algorithm of each token type, RS256, ES256, EdDSA or HS256
+    pub access_token_algorithm: Algorithm,
+    pub refresh_token_algorithm: Algorithm,
+    // base64 encoded PEM keys, tokens are signed with the private key.
+    // With HS256 the private key is the base64 encoded secret, and there's no public key.
+    pub access_token_private_key: String,
+    pub access_token_public_key: String,
+    pub refresh_token_private_key: String,
+    pub refresh_token_public_key: String,
+    // the public keys (or HS256 secrets) of rotated out private keys, the tokens they signed are still accepted
+    pub access_token_retired_public_keys: Vec<String>,
+    pub refresh_token_retired_public_keys: Vec<String>,
+    // failed logins allowed per account and per ip before they are locked out,
+    // each further failure doubles the lockout from `login_backoff_base` seconds up to `login_lockout_max` minutes.
+    // The failures are forgotten `login_failure_window` minutes after the last one.
+    pub login_max_attempts: u64,
+    pub login_ip_max_attempts: u64,
+    pub login_backoff_base: u64,
+    pub login_lockout_max: u64,
+    pub login_failure_window: u64,
+    // minutes a user has to send the TOTP code after the password, with the mfa challenge token
+    pub mfa_challenge_max_age: u64,
+    // minutes a password reset link is valid
+    pub password_reset_max_age: u64,
+    // minutes an email verification link is valid
+    pub email_verification_max_age: u64,
+    // reject the logins of users who haven't verified their email yet
+    pub require_email_verification: bool,
+    // how emails are sent, `MAILER_WEBHOOK_URL` is only required by the webhook mailer
+    pub mailer: MailerKind,
+    pub mailer_webhook_url: String,
+    pub mail_from: String,
+    // the front-end the links in the emails point to, e.g. `{app_url}/reset-password?token=...`
+    pub app_url: String,
+    // the services allowed to introspect and revoke tokens
+    pub oauth_clients: Vec<OAuthClient>,
+    // the request limits of the route groups, the longest matching path prefix applies
+    pub rate_limits: Vec<RateLimitRule>,
+    // the proxies in front of the server, the client ip is only read from the forwarded headers
+    // when the peer is one of them
+    pub trusted_proxies: Vec<IpAddr>,
+}
+
+/// A client of the token introspection and revocation endpoints, authenticated by its secret.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OAuthClient {
+    pub id: String,
+    pub secret: String,
+}
+
+/// The limit of the requests to the routes under a path prefix,
+/// counted per user, oauth client or ip in a sliding window of `window` seconds.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateLimitRule {
+    pub path: String,
+    pub limit: u64,
+    pub window: u64,
+}
+
+impl RateLimitRule {
+    /// whether the path is under the prefix of the rule, `/api/v1/users` doesn't match `/api/v1/users_old`
+    pub fn matches(&self, path: &str) -> bool {
+        match path.strip_prefix(self.path.trim_end_matches('/')) {
+            Some(rest) => rest.is_empty() || rest.starts_with('/'),
+            None => false,
+        }
+    }
+}
+
+impl FromStr for RateLimitRule {
+    type Err = ();
+
+    // `path=requests/seconds`, e.g. `/api/v1/users=120/60`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (path, quota) = s.split_once('=').ok_or(())?;
+        let (limit, window) = quota.split_once('/').ok_or(())?;
+        let (path, limit, window) = (
+            path.trim(),
+            limit.trim().parse::<u64>().map_err(|_| ())?,
+            window.trim().parse::<u64>().map_err(|_| ())?,
+        );
+        if !path.starts_with('/') || limit == 0 || window == 0 {
+            return Err(());
+        }
+        Ok(RateLimitRule {
+            path: path.to_string(),
+            limit,
+            window,
+        })
+    }
+}
+
+/// The backend of the token store, see `repository::token_store`.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum TokenStoreKind {
+    #[default]
+    Redis,
+    Postgres,
+    // in the process memory, for tests and local development
+    Memory,
+}
+
+impl FromStr for TokenStoreKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "redis" => Ok(TokenStoreKind::Redis),
+            "postgres" => Ok(TokenStoreKind::Postgres),
+            "memory" => Ok(TokenStoreKind::Memory),
+            _ => Err(format!(
+                "unknown token store `{}`, expected redis, postgres or memory",
+                s
+            )),
+        }
+    }
+}
+
+/// How emails are sent, see `mailer`. It must be set, the default is only a placeholder.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum MailerKind {
+    // written to the log, for local development
+    #[default]
+    Log,
+    // posted as JSON to `MAILER_WEBHOOK_URL`, e.g. a relay in front of the mail provider
+    Webhook,
+}
+
+impl FromStr for MailerKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "log" => Ok(MailerKind::Log),
+            "webhook" => Ok(MailerKind::Webhook),
+            _ => Err(format!("unknown mailer `{}`, expected log or webhook", s)),
+        }
+    }
+}
+
+/// All the problems found while loading the configuration, reported together.
+#[derive(Debug, Display)]
+#[display("Invalid configuration: {}", _0.join("; "))]
+pub struct ConfigError(pub Vec<String>);
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// load the configuration from environment variables and the optional config file
+    pub fn load() -> Result<Self, ConfigError> {
+        let (file, mut errors) = match std::env::var("CONFIG_FILE") {
+            Ok(path) => read_config_file(&path, true),
+            Err(_) => read_config_file(DEFAULT_CONFIG_FILE, false),
+        };
+
+        let mut source = Source {
+            file,
+            env: |name: &str| std::env::var(name).ok(),
+            errors: Vec::new(),
+        };
+        let config = source.build();
+        errors.append(&mut source.errors);
+
+        if errors.is_empty() {
+            Ok(config)
+        } else {
+            Err(ConfigError(errors))
+        }
+    }
+}
+
+// read the TOML config file, a missing file is only an error if it's required
+fn read_config_file(path: &str, required: bool) -> (toml::Table, Vec<String>) {
+    match std::fs::read_to_string(path) {
+        Ok(content) => match content.parse::<toml::Table>() {
+            Ok(table) => (table, Vec::new()),
+            Err(e) => (
+                toml::Table::new(),
+                vec![format!("{} is not a valid TOML file: {}", path, e)],
+            ),
+        },
+        Err(e) if required || e.kind() != std::io::ErrorKind::NotFound => (
+            toml::Table::new(),
+            vec![format!("Failed to read config file {}: {}", path, e)],
+        ),
+        Err(_) => (toml::Table::new(), Vec::new()),
+    }
+}
+
+// where settings are looked up, errors are collected instead of returned on the first one
+struct Source<F> {
+    file: toml::Table,
+    env: F,
+    errors: Vec<String>,
+}
+
+impl<F: Fn(&str) -> Option<String>> Source<F> {
+    fn build(&mut self) -> Config {
+        let token_store = self.optional("TOKEN_STORE", TokenStoreKind::Redis);
+        // there is no default, a forgotten setting mustn't write the one-time tokens to the log
+        let mailer = self.required("MAILER");
+        Config {
+            server_port: self.optional("SERVER_PORT", 8000),
+            database_url: self.required("DATABASE_URL"),
+            token_store,
+            redis_url: if token_store == TokenStoreKind::Redis {
+                self.required("REDIS_URL")
+            } else {
+                self.optional("REDIS_URL", String::new())
+            },
+            access_token_max_age: self.required("ACCESS_TOKEN_MAXAGE"),
+            refresh_token_max_age: self.required("REFRESH_TOKEN_MAXAGE"),
+            stateless_fallback: self.optional("STATELESS_FALLBACK", false),
+            stateless_fallback_max_age: self.optional("STATELESS_FALLBACK_MAXAGE", 5),
+            jwt_issuer: self.optional("JWT_ISSUER", "pwr.ink".to_string()),
+            jwt_audience: self.optional("JWT_AUDIENCE", "pwr.ink".to_string()),
+            access_token_algorithm: self.optional("ACCESS_TOKEN_ALGORITHM", Algorithm::RS256),
+            refresh_token_algorithm: self.optional("REFRESH_TOKEN_ALGORITHM", Algorithm::RS256),
+            access_token_private_key: self.required("ACCESS_TOKEN_PRIVATE_KEY"),
+            // the public keys are checked with the algorithm by `KeyStore`, HS256 has none
+            access_token_public_key: self.optional("ACCESS_TOKEN_PUBLIC_KEY", String::new()),
+            refresh_token_private_key: self.required("REFRESH_TOKEN_PRIVATE_KEY"),
+            refresh_token_public_key: self.optional("REFRESH_TOKEN_PUBLIC_KEY", String::new()),
+            access_token_retired_public_keys: self.list("ACCESS_TOKEN_RETIRED_PUBLIC_KEYS"),
+            refresh_token_retired_public_keys: self.list("REFRESH_TOKEN_RETIRED_PUBLIC_KEYS"),
+            login_max_attempts: self.optional("LOGIN_MAX_ATTEMPTS", 5),
+            login_ip_max_attempts: self.optional("LOGIN_IP_MAX_ATTEMPTS", 20),
+            login_backoff_base: self.optional("LOGIN_BACKOFF_BASE", 30),
+            login_lockout_max: self.optional("LOGIN_LOCKOUT_MAX", 15),
+            login_failure_window: self.optional("LOGIN_FAILURE_WINDOW", 60),
+            mfa_challenge_max_age: self.optional("MFA_CHALLENGE_MAXAGE", 5),
+            password_reset_max_age: self.optional("PASSWORD_RESET_MAXAGE", 30),
+            email_verification_max_age: self.optional("EMAIL_VERIFICATION_MAXAGE", 1440),
+            require_email_verification: self.optional("REQUIRE_EMAIL_VERIFICATION", false),
+            mailer,
+            mailer_webhook_url: if mailer == MailerKind::Webhook {
+                self.required("MAILER_WEBHOOK_URL")
+            } else {
+                self.optional("MAILER_WEBHOOK_URL", String::new())
+            },
+            mail_from: self.optional("MAIL_FROM", "no-reply@pwr.ink".to_string()),
+            app_url: self.optional("APP_URL", "http://localhost:3000".to_string()),
+            oauth_clients: self.clients("OAUTH_CLIENTS"),
+            rate_limits: self.rate_limits("RATE_LIMITS"),
+            trusted_proxies: self.ips("TRUSTED_PROXIES"),
+        }
+    }
+
+    // the raw value of a setting, environment variables take precedence over the config file
+    fn value(&self, name: &str) -> Option<String> {
+        (self.env)(name).or_else(|| {
+            self.file
+                .get(&name.to_lowercase())
+                .map(|value| match value {
+                    toml::Value::String(s) => s.clone(),
+                    value => value.to_string(),
+                })
+        })
+    }
+
+    // a list setting, comma separated in the environment variable or an array in the config file
+    fn list(&self, name: &str) -> Vec<String> {
+        let values = match (self.env)(name) {
+            Some(value) => vec![value],
+            None => match self.file.get(&name.to_lowercase()) {
+                Some(toml::Value::Array(values)) => values
+                    .iter()
+                    .map(|value| match value {
+                        toml::Value::String(s) => s.clone(),
+                        value => value.to_string(),
+                    })
+                    .collect(),
+                Some(_) => self.value(name).into_iter().collect(),
+                None => Vec::new(),
+            },
+        };
+        values
+            .iter()
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+            .map(str::to_string)
+            .collect()
+    }
+
+    // the oauth clients, a list of `client_id:client_secret` entries.
+    // The entries aren't quoted in the errors, they hold secrets.
+    fn clients(&mut self, name: &str) -> Vec<OAuthClient> {
+        let entries = self.list(name);
+        let mut clients = Vec::new();
+        for (i, entry) in entries.iter().enumerate() {
+            match entry.split_once(':') {
+                Some((id, secret)) if !id.is_empty() && !secret.is_empty() => {
+                    clients.push(OAuthClient {
+                        id: id.to_string(),
+                        secret: secret.to_string(),
+                    })
+                }
+                _ => self.errors.push(format!(
+                    "{} entry {} is invalid: expected `client_id:client_secret`",
+                    name,
+                    i + 1
+                )),
+            }
+        }
+        clients
+    }
+
+    // the rate limits, a list of `path=requests/seconds` entries
+    fn rate_limits(&mut self, name: &str) -> Vec<RateLimitRule> {
+        let mut entries = self.list(name);
+        if entries.is_empty() {
+            entries = DEFAULT_RATE_LIMITS
+                .split(',')
+                .map(|entry| entry.trim().to_string())
+                .collect();
+        }
+        let mut rules = Vec::new();
+        for (i, entry) in entries.iter().enumerate() {
+            match entry.parse() {
+                Ok(rule) => rules.push(rule),
+                Err(()) => self.errors.push(format!(
+                    "{} entry {} is invalid: expected `path=requests/seconds`, got `{}`",
+                    name,
+                    i + 1,
+                    entry
+                )),
+            }
+        }
+        rules
+    }
+
+    // a list of ip addresses
+    fn ips(&mut self, name: &str) -> Vec<IpAddr> {
+        let mut ips = Vec::new();
+        for (i, entry) in self.list(name).iter().enumerate() {
+            match entry.parse() {
+                Ok(ip) => ips.push(ip),
+                Err(_) => self.errors.push(format!(
+                    "{} entry {} is invalid: expected an ip address, got `{}`",
+                    name,
+                    i + 1,
+                    entry
+                )),
+            }
+        }
+        ips
+    }
+
+    fn parse<T>(&mut self, name: &str, value: String) -> Option<T>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        value
+            .trim()
+            .parse::<T>()
+            .map_err(|e| self.errors.push(format!("{} is invalid: {}", name, e)))
+            .ok()
+    }
+
+    // a required setting, the default value is only a placeholder as the error is reported
+    fn required<T>(&mut self, name: &str) -> T
+    where
+        T: FromStr + Default,
+        T::Err: fmt::Display,
+    {
+        match self.value(name) {
+            Some(value) => self.parse(name, value).unwrap_or_default(),
+            None => {
+                self.errors.push(format!("{} must be set", name));
+                T::default()
+            }
+        }
+    }
+
+    fn optional<T>(&mut self, name: &str, default: T) -> T
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        match self.value(name) {
+            Some(value) => self.parse(name, value).unwrap_or(default),
+            None => default,
+        }
+    }
+}
+
+/// a valid configuration with the RS256 keys in `tests/fixtures`, only used by tests
+#[cfg(test)]
+pub fn test_config() -> Config {
+    use base64::{engine::general_purpose, Engine as _};
+
+    Config {
+        server_port: 8000,
+        database_url: String::new(),
+        token_store: TokenStoreKind::Memory,
+        redis_url: String::new(),
+        access_token_max_age: 15,
+        refresh_token_max_age: 1440,
+        stateless_fallback: false,
+        stateless_fallback_max_age: 5,
+        jwt_issuer: "pwr.ink".to_string(),
+        jwt_audience: "pwr.ink".to_string(),
+        access_token_algorithm: Algorithm::RS256,
+        refresh_token_algorithm: Algorithm::RS256,
+        access_token_private_key: general_purpose::STANDARD.encode(include_str!(
+            "../tests/fixtures/access_token_private_key.pem"
+        )),
+        access_token_public_key: general_purpose::STANDARD.encode(include_str!(
+            "../tests/fixtures/access_token_public_key.pem"
+        )),
+        refresh_token_private_key: general_purpose::STANDARD.encode(include_str!(
+            "../tests/fixtures/refresh_token_private_key.pem"
+        )),
+        refresh_token_public_key: general_purpose::STANDARD.encode(include_str!(
+            "../tests/fixtures/refresh_token_public_key.pem"
+        )),
+        access_token_retired_public_keys: Vec::new(),
+        refresh_token_retired_public_keys: Vec::new(),
+        login_max_attempts: 5,
+        login_ip_max_attempts: 20,
+        login_backoff_base: 30,
+        login_lockout_max: 15,
+        login_failure_window: 60,
+        mfa_challenge_max_age: 5,
+        password_reset_max_age: 30,
+        email_verification_max_age: 1440,
+        require_email_verification: false,
+        mailer: MailerKind::Log,
+        mailer_webhook_url: String::new(),
+        mail_from: "no-reply@pwr.ink".to_string(),
+        app_url: "https://pwr.ink".to_string(),
+        oauth_clients: vec![OAuthClient {
+            id: "billing".to_string(),
+            secret: "billing-secret".to_string(),
+        }],
+        rate_limits: Vec::new(),
+        trusted_proxies: Vec::new(),
+    }
+}
+
+// a source of the given environment variables, without a config file
+#[cfg(test)]
+fn env_source(vars: &[(&str, &str)]) -> Source<impl Fn(&str) -> Option<String>> {
+    let vars: Vec<(String, String)> = vars
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect();
+    Source {
+        file: toml::Table::new(),
+        env: move |name: &str| {
+            vars.iter()
+                .find(|(var, _)| var == name)
+                .map(|(_, value)| value.clone())
+        },
+        errors: Vec::new(),
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_config_errors() {
+    let file = r#"
+        database_url = "postgres://localhost/pwr"
+        redis_url = "redis://localhost"
+        access_token_maxage = 15
+        server_port = "http"
+        jwt_audience = "api.pwr.ink"
+        access_token_retired_public_keys = ["a", "b"]
+    "#
+    .parse::<toml::Table>()
+    .unwrap();
+    let mut source = env_source(&[
+        ("REDIS_URL", "redis://redis:6379"),
+        ("REFRESH_TOKEN_MAXAGE", "a day"),
+        ("ACCESS_TOKEN_ALGORITHM", "none"),
+        ("REFRESH_TOKEN_ALGORITHM", "EdDSA"),
+        ("REFRESH_TOKEN_RETIRED_PUBLIC_KEYS", "c, d,"),
+        ("OAUTH_CLIENTS", "billing:s3cr:et, reports"),
+        ("TRUSTED_PROXIES", "10.0.0.1, 2001:db8::1, proxy"),
+    ]);
+    source.file = file;
+
+    let config = source.build();
+    assert_eq!(config.database_url, "postgres://localhost/pwr");
+    assert_eq!(config.token_store, TokenStoreKind::Redis);
+    assert_eq!(config.redis_url, "redis://redis:6379");
+    assert_eq!(config.access_token_max_age, 15);
+    assert!(!config.stateless_fallback);
+    assert_eq!(config.stateless_fallback_max_age, 5);
+    assert!(!config.require_email_verification);
+    assert_eq!(config.email_verification_max_age, 1440);
+    assert_eq!(config.server_port, 8000);
+    assert_eq!(config.jwt_issuer, "pwr.ink");
+    assert_eq!(config.jwt_audience, "api.pwr.ink");
+    assert_eq!(config.access_token_retired_public_keys, ["a", "b"]);
+    assert_eq!(config.refresh_token_retired_public_keys, ["c", "d"]);
+    assert_eq!(config.refresh_token_algorithm, Algorithm::EdDSA);
+    assert_eq!(
+        config.oauth_clients,
+        [OAuthClient {
+            id: "billing".to_string(),
+            secret: "s3cr:et".to_string(),
+        }]
+    );
+
+    assert_eq!(
+        config.trusted_proxies,
+        [
+            "10.0.0.1".parse::<IpAddr>().unwrap(),
+            "2001:db8::1".parse().unwrap()
+        ]
+    );
+
+    let errors = ConfigError(source.errors);
+    assert_eq!(errors.0.len(), 8);
+    assert_eq!(errors.0[0], "MAILER must be set");
+    assert!(errors.0[1].starts_with("SERVER_PORT is invalid"));
+    assert!(errors.0[2].starts_with("REFRESH_TOKEN_MAXAGE is invalid"));
+    assert!(errors.0[3].starts_with("ACCESS_TOKEN_ALGORITHM is invalid"));
+    assert!(errors
+        .to_string()
+        .contains("ACCESS_TOKEN_PRIVATE_KEY must be set; REFRESH_TOKEN_PRIVATE_KEY must be set"));
+    assert_eq!(
+        errors.0[6],
+        "OAUTH_CLIENTS entry 2 is invalid: expected `client_id:client_secret`"
+    );
+    assert_eq!(
+        errors.0[7],
+        "TRUSTED_PROXIES entry 3 is invalid: expected an ip address, got `proxy`"
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn test_token_store_config() {
+    // redis is the default store, it needs `REDIS_URL`
+    let mut redis = env_source(&[]);
+    assert_eq!(redis.build().token_store, TokenStoreKind::Redis);
+    assert!(redis.errors.iter().any(|e| e == "REDIS_URL must be set"));
+
+    let mut postgres = env_source(&[("TOKEN_STORE", "Postgres")]);
+    assert_eq!(postgres.build().token_store, TokenStoreKind::Postgres);
+    assert!(!postgres.errors.iter().any(|e| e.starts_with("REDIS_URL")));
+
+    let mut unknown = env_source(&[("TOKEN_STORE", "etcd")]);
+    unknown.build();
+    assert!(unknown.errors[0].starts_with("TOKEN_STORE is invalid: unknown token store `etcd`"));
+}
+
+#[cfg(test)]
+#[test]
+fn test_mailer_config() {
+    // there is no default mailer, the log one must be chosen
+    let mut unset = env_source(&[]);
+    unset.build();
+    assert!(unset.errors.iter().any(|e| e == "MAILER must be set"));
+
+    let mut log = env_source(&[("MAILER", "log")]);
+    assert_eq!(log.build().mailer, MailerKind::Log);
+    assert!(!log.errors.iter().any(|e| e.starts_with("MAILER")));
+
+    // the webhook mailer needs its url
+    let mut webhook = env_source(&[("MAILER", "Webhook")]);
+    assert_eq!(webhook.build().mailer, MailerKind::Webhook);
+    assert!(webhook
+        .errors
+        .iter()
+        .any(|e| e == "MAILER_WEBHOOK_URL must be set"));
+
+    let mut unknown = env_source(&[("MAILER", "smtp")]);
+    unknown.build();
+    assert!(unknown
+        .errors
+        .iter()
+        .any(|e| e == "MAILER is invalid: unknown mailer `smtp`, expected log or webhook"));
+}
+
+#[cfg(test)]
+#[test]
+fn test_rate_limit_config() {
+    let rules = env_source(&[]).build().rate_limits;
+    assert_eq!(rules.len(), 5);
+    assert_eq!(
+        rules[3],
+        RateLimitRule {
+            path: "/api/v1/users/search".to_string(),
+            limit: 30,
+            window: 60,
+        }
+    );
+
+    let mut invalid = env_source(&[(
+        "RATE_LIMITS",
+        "/api/v1/users=100/60, users=1/1, /api/v1/health=0/60",
+    )]);
+    assert_eq!(invalid.build().rate_limits.len(), 1);
+    assert!(invalid.errors.contains(
+        &"RATE_LIMITS entry 2 is invalid: expected `path=requests/seconds`, got `users=1/1`"
+            .to_string()
+    ));
+    assert!(invalid
+        .errors
+        .iter()
+        .any(|e| e.starts_with("RATE_LIMITS entry 3 is invalid")));
+
+    let rule: RateLimitRule = "/api/v1/users/=10/60".parse().unwrap();
+    assert!(rule.matches("/api/v1/users"));
+    assert!(rule.matches("/api/v1/users/me"));
+    assert!(!rule.matches("/api/v1/users_old"));
+    assert!(!rule.matches("/api/v1/auth/login"));
+}
@@ -1,6 +1,7 @@
 use crate::handlers::Response;
 use derive_more::Display; // naming it clearly for illustration purposes
-use ntex::web::{HttpRequest, HttpResponse, WebResponseError};
+use ntex::http;
+use ntex::web::{ErrorRenderer, HttpRequest, HttpResponse, WebResponseError};
 
 #[derive(Debug, Display)]
 pub enum AppError {
@@ -10,21 +11,36 @@ pub enum AppError {
     BadRequest(String),
     #[display("Unauthorized")]
     Unauthorized,
+    #[display("Forbidden")]
+    Forbidden,
     #[display("Not Found")]
     NotFound,
-    #[display("Conflict")]
-    Conflict,
     #[display("Service Unavailable")]
     ServiceUnavailable,
+    // the seconds to wait before retrying
+    #[display("Too Many Requests")]
+    TooManyRequests(u64),
     #[display("User Already Exists")]
     UserAlreadyExists(String),
+    // the login of a user who hasn't verified their email, when it's required
+    #[display("Email Not Verified")]
+    EmailNotVerified,
 }
 
 // Implement the `std::error::Error` trait for `AppError`
 impl std::error::Error for AppError {}
 
+// Redis holds the sessions, the service is unavailable without it
+impl From<redis::RedisError> for AppError {
+    fn from(e: redis::RedisError) -> Self {
+        log::error!("Redis error: {}", e);
+        AppError::ServiceUnavailable
+    }
+}
+
 /// Ntex uses `ResponseError` for conversion of errors to a response
-impl WebResponseError for AppError {
+/// It's implemented for any error renderer, so middlewares can render it as well.
+impl<Err: ErrorRenderer> WebResponseError<Err> for AppError {
     fn error_response(&self, _: &HttpRequest) -> HttpResponse {
         match self {
             AppError::InternalServerError(ref message) => {
@@ -47,21 +63,34 @@ impl WebResponseError for AppError {
                 count: None,
                 data: None,
             }),
+            AppError::Forbidden => HttpResponse::Forbidden().json(&Response::<()> {
+                status: "failed".to_string(),
+                message: "Permission Denied".to_string(),
+                count: None,
+                data: None,
+            }),
             AppError::NotFound => HttpResponse::NotFound().json(&Response::<()> {
                 status: "failed".to_string(),
                 message: "User Not Found".to_string(),
                 count: None,
                 data: None,
             }),
-            AppError::Conflict => HttpResponse::Conflict().finish(),
             AppError::ServiceUnavailable => {
                 HttpResponse::ServiceUnavailable().json(&Response::<()> {
                     status: "failed".to_string(),
-                    message: "Internal Server Error".to_string(),
+                    message: "Service Unavailable".to_string(),
                     count: None,
                     data: None,
                 })
             }
+            AppError::TooManyRequests(retry_after) => HttpResponse::TooManyRequests()
+                .set_header(http::header::RETRY_AFTER, retry_after.to_string())
+                .json(&Response::<()> {
+                    status: "failed".to_string(),
+                    message: format!("Too Many Requests, retry after {} seconds", retry_after),
+                    count: None,
+                    data: None,
+                }),
 
             AppError::UserAlreadyExists(ref message) => {
                 HttpResponse::BadRequest().json(&Response::<()> {
@@ -71,6 +100,13 @@ impl WebResponseError for AppError {
                     data: None,
                 })
             }
+            AppError::EmailNotVerified => HttpResponse::Forbidden().json(&Response::<()> {
+                status: "failed".to_string(),
+                message: "Email not verified, open the link of the verification email first"
+                    .to_string(),
+                count: None,
+                data: None,
+            }),
         }
     }
 }
@@ -0,0 +1,181 @@
+use ntex::web;
+use std::sync::Arc;
+
+use crate::{
+    config::Config,
+    errors::AppError,
+    handlers::Response,
+    mailer::{self, Email},
+    models::{
+        email_verification::{self, ResendVerification, VerifyEmail},
+        user::{self, User, UserView},
+    },
+    utils::one_time_token,
+    AppState,
+};
+
+// The verification of the emails of new users. A single-use link with a random token is sent to
+// the email on registration, opening it marks the email as verified. Logins can be rejected until then.
+
+// seconds before another link can be sent to the same user, besides the rate limit of the route
+const RESEND_INTERVAL: i64 = 60;
+
+// how long a link is valid, e.g. `24 hours` or `90 minutes`
+fn validity(minutes: u64) -> String {
+    match minutes {
+        60 => "1 hour".to_string(),
+        m if m % 60 == 0 => format!("{} hours", m / 60),
+        m => format!("{} minutes", m),
+    }
+}
+
+// the email with the verification link, the token is only known by the user
+fn verification_email(config: &Config, to: &str, token: &str) -> Email {
+    Email {
+        to: to.to_string(),
+        subject: "Verify your email".to_string(),
+        body: format!(
+            "Welcome! Open the link below to verify your email, it's valid for {}:\n\
+             {}/verify-email?token={}\n\n\
+             If you didn't create an account, ignore this email.",
+            validity(config.email_verification_max_age),
+            config.app_url.trim_end_matches('/'),
+            token
+        ),
+    }
+}
+
+/// send a new verification link to the email of a user, the previous links stop working
+pub async fn send_verification(data: &AppState, user: &User) -> Result<(), AppError> {
+    let token = one_time_token::generate();
+    let token_hash = one_time_token::hash(&token);
+    let expires_at = chrono::Utc::now()
+        + chrono::Duration::minutes(data.config.email_verification_max_age as i64);
+    let mut conn = data.pool.get().map_err(|e| {
+        log::error!("Failed to get db connection: {}", e);
+        AppError::ServiceUnavailable
+    })?;
+    let (user_id, email) = (user.id, user.email.clone());
+    web::block(move || {
+        email_verification::create_verification_token(
+            &mut conn,
+            user_id,
+            &email,
+            &token_hash,
+            expires_at,
+        )
+    })
+    .await
+    .map_err(|e| {
+        log::error!("Failed to create an email verification token: {:?}", e);
+        AppError::InternalServerError(e.to_string())
+    })?;
+
+    mailer::send_later(
+        &data.mailer,
+        verification_email(&data.config, &user.email, &token),
+    );
+    Ok(())
+}
+
+// verify the email of a user with the token of a verification link
+// #[web::post("/auth/verify-email")]
+pub async fn verify_email(
+    data: web::types::State<Arc<AppState>>,
+    body: web::types::Json<VerifyEmail>,
+) -> Result<web::HttpResponse, AppError> {
+    if body.token.is_empty() {
+        return Err(AppError::BadRequest("Token is required".to_string()));
+    }
+
+    let mut conn = data.pool.get().map_err(|e| {
+        log::error!("Failed to get db connection: {}", e);
+
+        AppError::ServiceUnavailable
+    })?;
+    let token_hash = one_time_token::hash(&body.token);
+    let verified_user =
+        web::block(move || email_verification::verify_email(&mut conn, &token_hash))
+            .await
+            .map_err(|e| {
+                log::error!("Failed to verify email: {:?}", e);
+                AppError::InternalServerError(e.to_string())
+            })?
+            .ok_or_else(|| {
+                AppError::BadRequest("Invalid or expired verification token".to_string())
+            })?;
+    log::info!("email of user {} verified", verified_user.id);
+
+    Ok(web::HttpResponse::Ok().json(&Response::<UserView> {
+        status: "success".to_string(),
+        message: "Email verified successfully".to_string(),
+        count: None,
+        data: Some(verified_user.into()),
+    }))
+}
+
+// send a new verification link to an unverified email.
+// The answer is the same whether the email is registered, verified or was just sent a link
+// #[web::post("/auth/verify-email/resend")]
+pub async fn resend_verification(
+    data: web::types::State<Arc<AppState>>,
+    body: web::types::Json<ResendVerification>,
+) -> Result<web::HttpResponse, AppError> {
+    let email = body.email.trim().to_string();
+    if email.is_empty() {
+        return Err(AppError::BadRequest("Email is required".to_string()));
+    }
+
+    let mut conn = data.pool.get().map_err(|e| {
+        log::error!("Failed to get db connection: {}", e);
+
+        AppError::ServiceUnavailable
+    })?;
+    let pending_user = web::block(move || {
+        let Some(user) =
+            user::get_user_by_email(&mut conn, &email)?.filter(|user| !user.is_email_verified())
+        else {
+            return Ok(None);
+        };
+        let last_sent_at = email_verification::last_sent_at(&mut conn, user.id)?;
+        let throttled = last_sent_at.is_some_and(|sent_at| {
+            chrono::Utc::now() - sent_at < chrono::Duration::seconds(RESEND_INTERVAL)
+        });
+        Ok::<_, diesel::result::Error>((!throttled).then_some(user))
+    })
+    .await
+    .map_err(|e| {
+        log::error!("Failed to get user by email: {:?}", e);
+        AppError::InternalServerError(e.to_string())
+    })?;
+
+    match pending_user {
+        Some(user) => send_verification(&data, &user).await?,
+        None => log::info!("email verification not resent, unknown, verified or too soon"),
+    }
+
+    Ok(web::HttpResponse::Ok().json(&Response::<()> {
+        status: "success".to_string(),
+        message: "If the email is registered and not verified yet, a verification link has been sent to it"
+            .to_string(),
+        count: None,
+        data: None,
+    }))
+}
+
+#[cfg(test)]
+#[test]
+fn test_verification_email() {
+    let config = Config {
+        app_url: "https://app.pwr.ink/".to_string(),
+        ..crate::config::test_config()
+    };
+    let email = verification_email(&config, "elton@pwr.ink", "abc-123");
+    assert_eq!(email.to, "elton@pwr.ink");
+    assert!(email
+        .body
+        .contains("https://app.pwr.ink/verify-email?token=abc-123\n"));
+    assert!(email.body.contains("valid for 24 hours:"));
+    assert_eq!(validity(60), "1 hour");
+    assert_eq!(validity(90), "90 minutes");
+}
@@ -0,0 +1,27 @@
+use ntex::web;
+use std::sync::Arc;
+
+use crate::{
+    errors::AppError, handlers::Response, middleware::permission::CurrentUser,
+    repository::token_store::FallbackMetrics, AppState,
+};
+
+// how the stateless fallback was used, only admins are allowed
+// #[web::get("/metrics/fallback")]
+pub async fn get_fallback_metrics(
+    data: web::types::State<Arc<AppState>>,
+    current_user: CurrentUser,
+) -> Result<web::HttpResponse, AppError> {
+    current_user.require_admin()?;
+    let fallback = data
+        .fallback
+        .as_ref()
+        .ok_or_else(|| AppError::BadRequest("Stateless fallback is disabled".to_string()))?;
+
+    Ok(web::HttpResponse::Ok().json(&Response::<FallbackMetrics> {
+        status: "success".to_string(),
+        message: "Stateless fallback metrics".to_string(),
+        count: None,
+        data: Some(fallback.metrics()),
+    }))
+}
@@ -0,0 +1,256 @@
+use ntex::web;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+use crate::{
+    errors::AppError,
+    handlers::{
+        user::{begin_login_attempt, finish_login_attempt, start_session},
+        Response,
+    },
+    middleware::permission::CurrentUser,
+    models::{
+        mfa::{self, MfaCode, MfaEnrollment, MfaStatus},
+        user,
+    },
+    utils::{jwt, totp},
+    AppState,
+};
+
+// The second factor of the login, a TOTP code (RFC 6238) or a recovery code.
+// Users enroll a secret, confirm it with a code, and from then on the login returns a challenge token
+// which is exchanged with a code for the token pair at `/auth/mfa`.
+
+// the body of the mfa step of the login
+#[derive(Deserialize)]
+pub struct MfaLogin {
+    challenge_token: String,
+    code: String,
+}
+
+// get the mfa status of the current user
+// #[web::get("/users/me/mfa")]
+pub async fn get_my_mfa(
+    data: web::types::State<Arc<AppState>>,
+    current_user: CurrentUser,
+) -> Result<web::HttpResponse, AppError> {
+    let mut conn = data.pool.get().map_err(|e| {
+        log::error!("Failed to get db connection: {}", e);
+        AppError::ServiceUnavailable
+    })?;
+
+    let user_id = current_user.id;
+    let status = web::block(move || mfa::get_status(&mut conn, user_id))
+        .await
+        .map_err(|e| {
+            log::error!("Failed to get the mfa of user {}: {:?}", user_id, e);
+            AppError::InternalServerError(e.to_string())
+        })?;
+
+    Ok(web::HttpResponse::Ok().json(&Response::<MfaStatus> {
+        status: "success".to_string(),
+        message: if status.enabled {
+            "MFA is enabled".to_string()
+        } else {
+            "MFA is disabled".to_string()
+        },
+        count: None,
+        data: Some(status),
+    }))
+}
+
+// start the enrollment of the current user, a new secret is returned with its provisioning uri.
+// It isn't required until it's confirmed with a code, enrolling again replaces an unconfirmed secret.
+// #[web::post("/users/me/mfa")]
+pub async fn enroll_my_mfa(
+    data: web::types::State<Arc<AppState>>,
+    current_user: CurrentUser,
+) -> Result<web::HttpResponse, AppError> {
+    let mut conn = data.pool.get().map_err(|e| {
+        log::error!("Failed to get db connection: {}", e);
+        AppError::ServiceUnavailable
+    })?;
+
+    let user_id = current_user.id;
+    let secret = totp::generate_secret();
+    let new_secret = secret.clone();
+    let (user, enrolled) = web::block(move || {
+        let user = user::get_users_by_id(&mut conn, user_id)?;
+        let enrolled = mfa::start_enrollment(&mut conn, user_id, &new_secret)?;
+        Ok::<_, diesel::result::Error>((user, enrolled))
+    })
+    .await
+    .map_err(|e| {
+        log::error!("Failed to enroll the mfa of user {}: {:?}", user_id, e);
+        AppError::InternalServerError(e.to_string())
+    })?;
+    let user = user.into_iter().next().ok_or(AppError::NotFound)?;
+    if enrolled.is_none() {
+        return Err(AppError::BadRequest(
+            "MFA is already enabled, disable it first".to_string(),
+        ));
+    }
+
+    Ok(
+        web::HttpResponse::Created().json(&Response::<MfaEnrollment> {
+            status: "success".to_string(),
+            message: "Add the secret to your authenticator app, then confirm it with a code"
+                .to_string(),
+            count: None,
+            data: Some(MfaEnrollment {
+                otpauth_uri: totp::provisioning_uri(&data.config.jwt_issuer, &user.email, &secret),
+                secret,
+            }),
+        }),
+    )
+}
+
+// confirm the enrollment of the current user with a code of the new secret, the mfa is enabled.
+// The recovery codes are returned, they are only shown this once.
+// #[web::put("/users/me/mfa")]
+pub async fn confirm_my_mfa(
+    data: web::types::State<Arc<AppState>>,
+    current_user: CurrentUser,
+    body: web::types::Json<MfaCode>,
+) -> Result<web::HttpResponse, AppError> {
+    let mut conn = data.pool.get().map_err(|e| {
+        log::error!("Failed to get db connection: {}", e);
+        AppError::ServiceUnavailable
+    })?;
+
+    let user_id = current_user.id;
+    let recovery_codes = totp::generate_recovery_codes();
+    let codes = recovery_codes.clone();
+    let confirmed = web::block(move || {
+        let pending = mfa::get_mfa(&mut conn, user_id)?.filter(|mfa| !mfa.is_enabled());
+        let step = pending.and_then(|mfa| {
+            totp::verify(
+                &mfa.secret,
+                &body.code,
+                chrono::Utc::now().timestamp() as u64,
+            )
+        });
+        match step {
+            Some(step) => mfa::enable(&mut conn, user_id, step, &codes).map(|_| true),
+            None => Ok(false),
+        }
+    })
+    .await
+    .map_err(|e| {
+        log::error!("Failed to confirm the mfa of user {}: {:?}", user_id, e);
+        AppError::InternalServerError(e.to_string())
+    })?;
+    if !confirmed {
+        return Err(AppError::BadRequest(
+            "Invalid code, or there is no MFA enrollment to confirm".to_string(),
+        ));
+    }
+    log::info!(target: "security", "user {} enabled mfa", user_id);
+
+    #[derive(Serialize)]
+    struct RecoveryCodes {
+        recovery_codes: Vec<String>,
+    }
+
+    Ok(web::HttpResponse::Ok().json(&Response::<RecoveryCodes> {
+        status: "success".to_string(),
+        message: "MFA enabled, keep the recovery codes safe, they are only shown once".to_string(),
+        count: None,
+        data: Some(RecoveryCodes { recovery_codes }),
+    }))
+}
+
+// disable the mfa of the current user, a TOTP code or a recovery code is required
+// #[web::delete("/users/me/mfa")]
+pub async fn disable_my_mfa(
+    data: web::types::State<Arc<AppState>>,
+    current_user: CurrentUser,
+    body: web::types::Json<MfaCode>,
+) -> Result<web::HttpResponse, AppError> {
+    let mut conn = data.pool.get().map_err(|e| {
+        log::error!("Failed to get db connection: {}", e);
+        AppError::ServiceUnavailable
+    })?;
+
+    let user_id = current_user.id;
+    let disabled = web::block(move || {
+        if !mfa::verify_code(&mut conn, user_id, &body.code)? {
+            return Ok(false);
+        }
+        mfa::disable(&mut conn, user_id).map(|_| true)
+    })
+    .await
+    .map_err(|e| {
+        log::error!("Failed to disable the mfa of user {}: {:?}", user_id, e);
+        AppError::InternalServerError(e.to_string())
+    })?;
+    if !disabled {
+        return Err(AppError::BadRequest("Invalid code".to_string()));
+    }
+    log::info!(target: "security", "user {} disabled mfa", user_id);
+
+    Ok(web::HttpResponse::Ok().json(&Response::<()> {
+        status: "success".to_string(),
+        message: "MFA disabled".to_string(),
+        count: None,
+        data: None,
+    }))
+}
+
+// the second step of the login, exchange the challenge token and a code for the token pair.
+// A challenge token is only exchanged once, and rejected after a few wrong codes.
+// With redis, the wrong codes also count as failed logins of the account, it's locked out the same way.
+// #[web::post("/auth/mfa")]
+pub async fn mfa_login(
+    data: web::types::State<Arc<AppState>>,
+    client: jwt::ClientInfo,
+    body: web::types::Json<MfaLogin>,
+) -> Result<web::HttpResponse, AppError> {
+    let challenge = jwt::decode_mfa_challenge(
+        &data.config,
+        &data.keys,
+        &body.challenge_token,
+        &jwt::SystemClock,
+    )
+    .map_err(|e| {
+        log::error!("Invalid mfa challenge token: {}", e);
+        AppError::Unauthorized
+    })?;
+    let user_id = challenge.user_id;
+
+    let mut conn = data.pool.get().map_err(|e| {
+        log::error!("Failed to get db connection: {}", e);
+
+        AppError::ServiceUnavailable
+    })?;
+    let users = web::block(move || user::get_users_by_id(&mut conn, user_id))
+        .await
+        .map_err(|e| {
+            log::error!("Failed to get user: {:?}", e);
+            AppError::InternalServerError(e.to_string())
+        })?;
+    let user = users.into_iter().next().ok_or(AppError::Unauthorized)?;
+
+    let attempt = begin_login_attempt(&data, &user.email, &client).await?;
+
+    let mut conn = data.pool.get().map_err(|e| {
+        log::error!("Failed to get db connection: {}", e);
+
+        AppError::ServiceUnavailable
+    })?;
+    let code = body.code.clone();
+    let verified = web::block(move || mfa::verify_challenge(&mut conn, &challenge, &code))
+        .await
+        .map_err(|e| {
+            log::error!("Failed to verify the mfa code of user {}: {:?}", user_id, e);
+            AppError::InternalServerError(e.to_string())
+        })?;
+
+    if !verified {
+        log::warn!(target: "security", "invalid mfa code for user {}", user_id);
+        return Err(AppError::Unauthorized);
+    }
+
+    finish_login_attempt(attempt, true).await;
+    start_session(&data, user, &client).await
+}
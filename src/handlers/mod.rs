@@ -1,7 +1,16 @@
 use ntex::http;
 use ntex::web::{self, Error};
 use serde::Serialize;
+use std::sync::Arc;
 
+use crate::AppState;
+
+pub mod email_verification;
+pub mod metrics;
+pub mod mfa;
+pub mod oauth;
+pub mod password;
+pub mod session;
 pub mod user;
 #[derive(Serialize)]
 pub struct Response<T> {
@@ -21,6 +30,14 @@ async fn health() -> Result<web::HttpResponse, Error> {
     }))
 }
 
+/// the public keys verifying access tokens, as a JWK set (RFC 7517) so other services can fetch them
+async fn jwks(data: web::types::State<Arc<AppState>>) -> Result<web::HttpResponse, Error> {
+    Ok(web::HttpResponse::Ok()
+        // keys are rotated rarely, but a retired key must not stay cached for long
+        .set_header(http::header::CACHE_CONTROL, "public, max-age=300")
+        .json(&data.keys.jwks()))
+}
+
 // not found handler
 async fn not_found_error() -> Result<web::HttpResponse, Error> {
     Ok(web::HttpResponse::NotFound().json(&Response::<()> {
@@ -41,6 +58,12 @@ impl web::guard::Guard for AuthorizationHeader {
 
 /// configure routes
 pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/.well-known/jwks.json").route(web::get().to(jwks)));
+    // authenticated by the client credentials, not by an access token
+    cfg.service(web::scope("/oauth").service((
+        web::resource("/introspect").route(web::post().to(oauth::introspect)),
+        web::resource("/revoke").route(web::post().to(oauth::revoke)),
+    )));
     cfg.service(
         web::scope("/api/v1")
             .service((
@@ -48,14 +71,61 @@ pub fn config(cfg: &mut web::ServiceConfig) {
                 web::resource("/users")
                     .guard(AuthorizationHeader)
                     .route(web::post().to(user::create_user))
-                    .route(web::get().to(user::get_user_by_id_or_name::<user::Info>))
+                    .route(web::get().to(user::get_user_by_id_or_name))
                     .route(web::put().to(user::update_user_by_id))
-                    .route(web::delete().to(user::delete_user_by_id::<user::UserQuery>)),
+                    .route(web::delete().to(user::delete_user_by_id)),
+                web::resource("/users/me")
+                    .guard(AuthorizationHeader)
+                    .route(web::get().to(user::get_me))
+                    .route(web::patch().to(user::update_me))
+                    .route(web::delete().to(user::delete_me)),
+                web::resource("/users/me/password")
+                    .guard(AuthorizationHeader)
+                    .route(web::post().to(user::change_my_password)),
+                web::resource("/users/me/mfa")
+                    .guard(AuthorizationHeader)
+                    .route(web::get().to(mfa::get_my_mfa))
+                    .route(web::post().to(mfa::enroll_my_mfa))
+                    .route(web::put().to(mfa::confirm_my_mfa))
+                    .route(web::delete().to(mfa::disable_my_mfa)),
+                web::resource("/users/me/sessions")
+                    .guard(AuthorizationHeader)
+                    .route(web::get().to(session::get_my_sessions))
+                    .route(web::delete().to(session::revoke_my_session)),
+                web::resource("/users/me/sessions/others")
+                    .guard(AuthorizationHeader)
+                    .route(web::delete().to(session::revoke_my_other_sessions)),
+                web::resource("/users/sessions")
+                    .guard(AuthorizationHeader)
+                    .route(web::delete().to(session::revoke_user_sessions)),
+                web::resource("/users/lockout")
+                    .guard(AuthorizationHeader)
+                    .route(web::delete().to(user::unlock_user)),
+                web::resource("/users/password")
+                    .guard(AuthorizationHeader)
+                    .route(web::post().to(user::set_user_password)),
                 web::resource("/users/search")
                     .guard(AuthorizationHeader)
                     .route(web::post().to(user::search_users)),
+                web::resource("/metrics/fallback")
+                    .guard(AuthorizationHeader)
+                    .route(web::get().to(metrics::get_fallback_metrics)),
                 web::resource("/auth/login").route(web::post().to(user::user_login)),
-                web::resource("/auth/register").route(web::post().to(user::create_user)),
+                // the challenge token of the login is in the body, there's no access token yet
+                web::resource("/auth/mfa").route(web::post().to(mfa::mfa_login)),
+                web::resource("/auth/password/forgot")
+                    .route(web::post().to(password::forgot_password)),
+                web::resource("/auth/password/reset")
+                    .route(web::post().to(password::reset_password)),
+                web::resource("/auth/register").route(web::post().to(user::register_user)),
+                web::resource("/auth/verify-email")
+                    .route(web::post().to(email_verification::verify_email)),
+                web::resource("/auth/verify-email/resend")
+                    .route(web::post().to(email_verification::resend_verification)),
+                web::resource("/auth/logout")
+                    // logout should carry an access token, even if it's expired
+                    .guard(AuthorizationHeader)
+                    .route(web::post().to(user::user_logout)),
                 web::resource("/auth/refresh_token")
                     // refresh token should carry a refresh token
                     .guard(AuthorizationHeader)
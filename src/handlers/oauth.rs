@@ -0,0 +1,387 @@
+use base64::{engine::general_purpose, Engine as _};
+use derive_more::Display;
+use ntex::http::{self, StatusCode};
+use ntex::web::{self, ErrorRenderer, HttpRequest, HttpResponse, WebResponseError};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+
+use crate::{
+    config::OAuthClient,
+    errors::AppError,
+    models::user::{self, Role},
+    utils::jwt::{self, Claims, TokenType},
+    AppState,
+};
+
+// The token introspection (RFC 7662) and revocation (RFC 7009) endpoints, for the other services
+// of the platform. They are authenticated by the client credentials in `OAUTH_CLIENTS`,
+// and answer in the OAuth format instead of the api envelope.
+
+/// The form of the introspection and revocation requests.
+/// The client credentials may be sent in the form instead of the `Authorization` header.
+#[derive(Deserialize)]
+pub struct TokenRequest {
+    token: Option<String>,
+    // `access_token` or `refresh_token`, the other type is tried as well
+    token_type_hint: Option<String>,
+    client_id: Option<String>,
+    client_secret: Option<String>,
+}
+
+/// The errors of the oauth endpoints, rendered as RFC 6749 error responses.
+#[derive(Debug, Display)]
+pub enum OAuthError {
+    #[display("invalid_client")]
+    InvalidClient,
+    #[display("invalid_request")]
+    InvalidRequest,
+    #[display("temporarily_unavailable")]
+    TemporarilyUnavailable,
+    #[display("server_error")]
+    ServerError,
+}
+
+impl std::error::Error for OAuthError {}
+
+impl From<AppError> for OAuthError {
+    fn from(e: AppError) -> Self {
+        match e {
+            AppError::ServiceUnavailable => OAuthError::TemporarilyUnavailable,
+            _ => OAuthError::ServerError,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: String,
+}
+
+impl<Err: ErrorRenderer> WebResponseError<Err> for OAuthError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            OAuthError::InvalidClient => StatusCode::UNAUTHORIZED,
+            OAuthError::InvalidRequest => StatusCode::BAD_REQUEST,
+            OAuthError::TemporarilyUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+            OAuthError::ServerError => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self, _: &HttpRequest) -> HttpResponse {
+        let mut response = HttpResponse::build(WebResponseError::<Err>::status_code(self));
+        response.set_header(http::header::CACHE_CONTROL, "no-store");
+        if let OAuthError::InvalidClient = self {
+            response.set_header(http::header::WWW_AUTHENTICATE, "Basic realm=\"oauth\"");
+        }
+        response.json(&ErrorBody {
+            error: self.to_string(),
+        })
+    }
+}
+
+/// The introspection response, only `active` is set for an inactive token.
+#[derive(Debug, Serialize)]
+pub struct Introspection {
+    active: bool,
+    #[serde(flatten)]
+    token: Option<IntrospectedToken>,
+}
+
+#[derive(Debug, Serialize)]
+struct IntrospectedToken {
+    // the RFC 6749 token type, the tokens are bearer tokens
+    token_type: &'static str,
+    // whether it's an access or a refresh token, with the values of `token_type_hint`
+    token_use: &'static str,
+    sub: String,
+    iss: String,
+    aud: String,
+    iat: usize,
+    nbf: usize,
+    exp: usize,
+    jti: String,
+    sid: String,
+    // the current role of the user, the one in the token may be outdated
+    role: Role,
+}
+
+impl Introspection {
+    fn inactive() -> Self {
+        Introspection {
+            active: false,
+            token: None,
+        }
+    }
+
+    fn active(kind: TokenType, claims: Claims, role: Role) -> Self {
+        Introspection {
+            active: true,
+            token: Some(IntrospectedToken {
+                token_type: "Bearer",
+                token_use: match kind {
+                    TokenType::AccessToken => "access_token",
+                    TokenType::RefreshToken => "refresh_token",
+                },
+                sub: claims.sub,
+                iss: claims.iss,
+                aud: claims.aud,
+                iat: claims.iat,
+                nbf: claims.nbf,
+                exp: claims.exp,
+                jti: claims.token_id,
+                sid: claims.sid,
+                role,
+            }),
+        }
+    }
+}
+
+// the client authenticated by HTTP Basic or by the form (RFC 6749 section 2.3.1)
+fn authenticate<'a>(
+    clients: &'a [OAuthClient],
+    headers: &http::HeaderMap,
+    form: &TokenRequest,
+) -> Result<&'a OAuthClient, OAuthError> {
+    let credentials = match headers.get(http::header::AUTHORIZATION) {
+        Some(value) => {
+            let value = value.to_str().map_err(|_| OAuthError::InvalidClient)?;
+            let (scheme, encoded) = value
+                .trim()
+                .split_once(' ')
+                .ok_or(OAuthError::InvalidClient)?;
+            if !scheme.eq_ignore_ascii_case("Basic") {
+                return Err(OAuthError::InvalidClient);
+            }
+            let decoded = general_purpose::STANDARD
+                .decode(encoded.trim())
+                .ok()
+                .and_then(|decoded| String::from_utf8(decoded).ok())
+                .ok_or(OAuthError::InvalidClient)?;
+            let (id, secret) = decoded.split_once(':').ok_or(OAuthError::InvalidClient)?;
+            (id.to_string(), secret.to_string())
+        }
+        None => match (&form.client_id, &form.client_secret) {
+            (Some(id), Some(secret)) => (id.clone(), secret.clone()),
+            _ => return Err(OAuthError::InvalidClient),
+        },
+    };
+
+    // the secrets are compared by their digests, so the comparison doesn't leak them
+    let (id, secret) = credentials;
+    clients
+        .iter()
+        .find(|client| client.id == id && Sha256::digest(&client.secret) == Sha256::digest(&secret))
+        .ok_or(OAuthError::InvalidClient)
+}
+
+/// the oauth client authenticated by the `Authorization: Basic` header of a request, if any
+pub fn basic_client<'a>(
+    clients: &'a [OAuthClient],
+    headers: &http::HeaderMap,
+) -> Option<&'a OAuthClient> {
+    if !headers.contains_key(http::header::AUTHORIZATION) {
+        return None;
+    }
+    let form = TokenRequest {
+        token: None,
+        token_type_hint: None,
+        client_id: None,
+        client_secret: None,
+    };
+    authenticate(clients, headers, &form).ok()
+}
+
+// decode a token of either type, the hinted one is tried first
+fn decode(
+    data: &AppState,
+    token: &str,
+    hint: Option<&str>,
+    validate_exp: bool,
+) -> Option<(TokenType, Claims)> {
+    let kinds = match hint {
+        Some("refresh_token") => [TokenType::RefreshToken, TokenType::AccessToken],
+        _ => [TokenType::AccessToken, TokenType::RefreshToken],
+    };
+    kinds.into_iter().find_map(|kind| {
+        let claims = if validate_exp {
+            jwt::decode_token(&data.config, &data.keys, kind, token)
+        } else {
+            jwt::decode_expired_token(&data.config, &data.keys, kind, token)
+        };
+        claims.ok().map(|claims| (kind, claims))
+    })
+}
+
+// whether a decoded token is still recorded in its session, for a user who isn't deleted.
+// It returns the current role of the user.
+async fn active_role(
+    data: &AppState,
+    kind: TokenType,
+    claims: &Claims,
+) -> Result<Option<Role>, AppError> {
+    let Ok(principal) = jwt::Principal::try_from(claims.clone()) else {
+        return Ok(None);
+    };
+    if !jwt::is_active(data, &principal, kind).await? {
+        return Ok(None);
+    }
+
+    let mut conn = data.pool.get().map_err(|e| {
+        log::error!("Failed to get db connection: {}", e);
+        AppError::ServiceUnavailable
+    })?;
+    let users = web::block(move || user::get_users_by_id(&mut conn, principal.user_id))
+        .await
+        .map_err(|e| {
+            log::error!("Failed to get user: {:?}", e);
+            AppError::InternalServerError(e.to_string())
+        })?;
+    Ok(users.first().map(|user| user.role))
+}
+
+// tell whether a token is active, and who it was issued to
+// #[web::post("/oauth/introspect")]
+pub async fn introspect(
+    data: web::types::State<Arc<AppState>>,
+    req: HttpRequest,
+    form: web::types::Form<TokenRequest>,
+) -> Result<HttpResponse, OAuthError> {
+    let client = authenticate(&data.config.oauth_clients, req.headers(), &form)?;
+    let token = form.token.as_deref().ok_or(OAuthError::InvalidRequest)?;
+
+    let introspection = match decode(&data, token, form.token_type_hint.as_deref(), true) {
+        Some((kind, claims)) => match active_role(&data, kind, &claims).await? {
+            Some(role) => Introspection::active(kind, claims, role),
+            None => Introspection::inactive(),
+        },
+        None => Introspection::inactive(),
+    };
+    log::info!(
+        "client {} introspected a token, active: {}",
+        client.id,
+        introspection.active
+    );
+
+    Ok(HttpResponse::Ok()
+        .set_header(http::header::CACHE_CONTROL, "no-store")
+        .json(&introspection))
+}
+
+// revoke a token, the whole session of the token is revoked with it.
+// Invalid and expired tokens are ignored, there is nothing the client could do about them (RFC 7009 section 2.2).
+// #[web::post("/oauth/revoke")]
+pub async fn revoke(
+    data: web::types::State<Arc<AppState>>,
+    req: HttpRequest,
+    form: web::types::Form<TokenRequest>,
+) -> Result<HttpResponse, OAuthError> {
+    let client = authenticate(&data.config.oauth_clients, req.headers(), &form)?;
+    let token = form.token.as_deref().ok_or(OAuthError::InvalidRequest)?;
+
+    let principal = decode(&data, token, form.token_type_hint.as_deref(), false)
+        .and_then(|(_, claims)| jwt::Principal::try_from(claims).ok());
+    if let Some(principal) = principal {
+        let revoked =
+            jwt::revoke_session(&data, principal.user_id as usize, &principal.session_id).await?;
+        if revoked.is_some() {
+            log::info!(
+                "client {} revoked session {} of user {}",
+                client.id,
+                principal.session_id,
+                principal.user_id
+            );
+        }
+    }
+
+    Ok(HttpResponse::Ok()
+        .set_header(http::header::CACHE_CONTROL, "no-store")
+        .finish())
+}
+
+#[cfg(test)]
+#[test]
+fn test_client_authentication() {
+    let clients = crate::config::test_config().oauth_clients;
+    let form = |id: Option<&str>, secret: Option<&str>| TokenRequest {
+        token: None,
+        token_type_hint: None,
+        client_id: id.map(str::to_string),
+        client_secret: secret.map(str::to_string),
+    };
+    let basic = |credentials: &str| {
+        let mut headers = http::HeaderMap::new();
+        headers.insert(
+            http::header::AUTHORIZATION,
+            http::header::HeaderValue::from_str(&format!(
+                "Basic {}",
+                general_purpose::STANDARD.encode(credentials)
+            ))
+            .unwrap(),
+        );
+        headers
+    };
+    let empty = http::HeaderMap::new();
+
+    let client = authenticate(
+        &clients,
+        &basic("billing:billing-secret"),
+        &form(None, None),
+    );
+    assert_eq!(client.unwrap().id, "billing");
+    let client = authenticate(
+        &clients,
+        &empty,
+        &form(Some("billing"), Some("billing-secret")),
+    );
+    assert_eq!(client.unwrap().id, "billing");
+
+    for (headers, form) in [
+        (basic("billing:wrong"), form(None, None)),
+        (basic("reports:billing-secret"), form(None, None)),
+        (basic("billing"), form(None, None)),
+        // the header takes precedence over the form
+        (
+            basic("billing:wrong"),
+            form(Some("billing"), Some("billing-secret")),
+        ),
+        (empty.clone(), form(Some("billing"), None)),
+        (empty.clone(), form(None, None)),
+    ] {
+        assert!(matches!(
+            authenticate(&clients, &headers, &form),
+            Err(OAuthError::InvalidClient)
+        ));
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_introspection() {
+    let json = serde_json::to_value(Introspection::inactive()).unwrap();
+    assert_eq!(json, serde_json::json!({ "active": false }));
+
+    let config = crate::config::test_config();
+    let claims = Claims::new(
+        &config,
+        TokenType::RefreshToken,
+        1,
+        Role::User,
+        "01HSJARKXDAH23Z8SF6ZY475TS",
+        &jwt::SystemClock,
+    );
+    let token_id = claims.token_id.clone();
+    let json = serde_json::to_value(Introspection::active(
+        TokenType::RefreshToken,
+        claims,
+        Role::Admin,
+    ))
+    .unwrap();
+    assert_eq!(json["active"], true);
+    assert_eq!(json["token_type"], "Bearer");
+    assert_eq!(json["token_use"], "refresh_token");
+    assert_eq!(json["sub"], "1");
+    assert_eq!(json["jti"], token_id.as_str());
+    assert_eq!(json["sid"], "01HSJARKXDAH23Z8SF6ZY475TS");
+    assert_eq!(json["role"], "admin");
+}
@@ -0,0 +1,168 @@
+use ntex::web;
+use std::sync::Arc;
+
+use crate::{
+    config::Config,
+    errors::AppError,
+    handlers::Response,
+    mailer::{self, Email},
+    models::{
+        password_reset::{self, ForgotPassword, ResetPassword},
+        user,
+    },
+    repository::login_attempts,
+    utils::{jwt, one_time_token},
+    AppState,
+};
+
+// The password reset of users who forgot their password. A single-use link with a random token
+// is sent to their email, and the token sets a new password before it expires.
+// The answers are the same whether the email is registered or not, so they don't tell it.
+
+// seconds before another link can be sent to the same user, besides the rate limit of the route.
+// A new link replaces the previous one, so it also keeps the link the user is opening from being replaced
+const RESEND_INTERVAL: i64 = 60;
+
+// the email with the reset link, the token is only known by the user
+fn password_reset_email(config: &Config, to: &str, token: &str) -> Email {
+    Email {
+        to: to.to_string(),
+        subject: "Reset your password".to_string(),
+        body: format!(
+            "Someone asked to reset the password of your account.\n\n\
+             Open the link below to choose a new password, it's valid for {} minutes:\n\
+             {}/reset-password?token={}\n\n\
+             If it wasn't you, ignore this email, your password won't change.",
+            config.password_reset_max_age,
+            config.app_url.trim_end_matches('/'),
+            token
+        ),
+    }
+}
+
+// send a password reset link to the email of a user
+// #[web::post("/auth/password/forgot")]
+pub async fn forgot_password(
+    data: web::types::State<Arc<AppState>>,
+    body: web::types::Json<ForgotPassword>,
+) -> Result<web::HttpResponse, AppError> {
+    let email = body.email.trim().to_string();
+    if email.is_empty() {
+        return Err(AppError::BadRequest("Email is required".to_string()));
+    }
+
+    let token = one_time_token::generate();
+    let token_hash = one_time_token::hash(&token);
+    let expires_at =
+        chrono::Utc::now() + chrono::Duration::minutes(data.config.password_reset_max_age as i64);
+    let mut conn = data.pool.get().map_err(|e| {
+        log::error!("Failed to get db connection: {}", e);
+        AppError::ServiceUnavailable
+    })?;
+    let user = web::block(move || {
+        let Some(user) = user::get_user_by_email(&mut conn, &email)? else {
+            return Ok(None);
+        };
+        let last_sent_at = password_reset::last_sent_at(&mut conn, user.id)?;
+        if last_sent_at.is_some_and(|sent_at| {
+            chrono::Utc::now() - sent_at < chrono::Duration::seconds(RESEND_INTERVAL)
+        }) {
+            log::info!(target: "security", "password reset of user {} requested too soon", user.id);
+            return Ok(None);
+        }
+        password_reset::create_reset_token(&mut conn, user.id, &token_hash, expires_at)?;
+        Ok::<_, diesel::result::Error>(Some(user))
+    })
+    .await
+    .map_err(|e| {
+        log::error!("Failed to create a password reset token: {:?}", e);
+        AppError::InternalServerError(e.to_string())
+    })?;
+
+    // the email is sent in the background, it doesn't delay the answer
+    match user {
+        Some(user) => {
+            log::info!(target: "security", "password reset requested for user {}", user.id);
+            mailer::send_later(
+                &data.mailer,
+                password_reset_email(&data.config, &user.email, &token),
+            );
+        }
+        None => log::info!("password reset not sent, unknown email or too soon"),
+    }
+
+    Ok(web::HttpResponse::Ok().json(&Response::<()> {
+        status: "success".to_string(),
+        message: "If the email is registered, a password reset link has been sent to it"
+            .to_string(),
+        count: None,
+        data: None,
+    }))
+}
+
+// set a new password with the token of a reset link, all the sessions of the user are revoked
+// #[web::post("/auth/password/reset")]
+pub async fn reset_password(
+    data: web::types::State<Arc<AppState>>,
+    body: web::types::Json<ResetPassword>,
+) -> Result<web::HttpResponse, AppError> {
+    if body.token.is_empty() || body.new_password.is_empty() {
+        return Err(AppError::BadRequest(
+            "Token and new password are required".to_string(),
+        ));
+    }
+
+    let mut conn = data.pool.get().map_err(|e| {
+        log::error!("Failed to get db connection: {}", e);
+
+        AppError::ServiceUnavailable
+    })?;
+    let token_hash = one_time_token::hash(&body.token);
+    let updated_user = web::block(move || {
+        password_reset::reset_password(&mut conn, &token_hash, &body.new_password)
+    })
+    .await
+    .map_err(|e| {
+        log::error!("Failed to reset password: {:?}", e);
+        AppError::InternalServerError(e.to_string())
+    })?
+    .ok_or_else(|| AppError::BadRequest("Invalid or expired reset token".to_string()))?;
+
+    // whoever knew the old password is logged out
+    let user_id = updated_user.id;
+    jwt::revoke_user_sessions(&data, user_id as usize)
+        .await
+        .map_err(|e| {
+            log::error!("Failed to revoke all tokens of user {}: {:?}", user_id, e);
+            e
+        })?;
+    // a lockout of the account doesn't keep its owner out after the reset
+    if let Some(redis) = &data.redis {
+        if let Err(e) = login_attempts::reset(redis, &updated_user.email).await {
+            log::error!("Failed to reset the login attempts: {}", e);
+        }
+    }
+    log::info!(target: "security", "password of user {} reset", user_id);
+
+    Ok(web::HttpResponse::Ok().json(&Response::<()> {
+        status: "success".to_string(),
+        message: "Password reset successfully, please log in again".to_string(),
+        count: None,
+        data: None,
+    }))
+}
+
+#[cfg(test)]
+#[test]
+fn test_password_reset_email() {
+    let config = Config {
+        app_url: "https://app.pwr.ink/".to_string(),
+        ..crate::config::test_config()
+    };
+    let email = password_reset_email(&config, "elton@pwr.ink", "abc-123");
+    assert_eq!(email.to, "elton@pwr.ink");
+    assert!(email
+        .body
+        .contains("https://app.pwr.ink/reset-password?token=abc-123\n"));
+    assert!(email.body.contains("valid for 30 minutes"));
+}
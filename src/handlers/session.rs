@@ -0,0 +1,145 @@
+use ntex::web;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+use crate::{
+    errors::AppError, handlers::Response, middleware::permission::CurrentUser,
+    repository::token_store::Session, utils::jwt, AppState,
+};
+
+#[derive(Deserialize)]
+pub struct SessionQuery {
+    // id of the session
+    id: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct UserSessionsQuery {
+    // id of the user
+    id: Option<i32>,
+}
+
+#[derive(Serialize)]
+pub struct SessionView {
+    #[serde(flatten)]
+    session: Session,
+    // whether it's the session of the request
+    current: bool,
+}
+
+// list the active sessions of the current user
+// #[web::get("/users/me/sessions")]
+pub async fn get_my_sessions(
+    data: web::types::State<Arc<AppState>>,
+    current_user: CurrentUser,
+) -> Result<web::HttpResponse, AppError> {
+    let sessions = jwt::list_sessions(&data, current_user.id as usize)
+        .await
+        .map_err(|e| {
+            log::error!("Failed to list sessions: {:?}", e);
+            e
+        })?;
+
+    let sessions: Vec<SessionView> = sessions
+        .into_iter()
+        .map(|session| SessionView {
+            current: session.id == current_user.session_id,
+            session,
+        })
+        .collect();
+    Ok(web::HttpResponse::Ok().json(&Response::<Vec<SessionView>> {
+        status: "success".to_string(),
+        message: "Sessions found".to_string(),
+        count: Some(sessions.len() as i64),
+        data: Some(sessions),
+    }))
+}
+
+// revoke a session of the current user, its access token and refresh token stop working at once
+// #[web::delete("/users/me/sessions")]
+pub async fn revoke_my_session(
+    data: web::types::State<Arc<AppState>>,
+    current_user: CurrentUser,
+    query: web::types::Query<SessionQuery>,
+) -> Result<web::HttpResponse, AppError> {
+    let session_id = query
+        .id
+        .as_deref()
+        .ok_or_else(|| AppError::BadRequest("Session id is required".to_string()))?;
+
+    let revoked = jwt::revoke_session(&data, current_user.id as usize, session_id)
+        .await
+        .map_err(|e| {
+            log::error!("Failed to revoke session {}: {:?}", session_id, e);
+            e
+        })?;
+    // the sessions of other users are reported as missing as well
+    if revoked.is_none() {
+        return Err(AppError::BadRequest(format!(
+            "Session `{}` not found",
+            session_id
+        )));
+    }
+
+    Ok(web::HttpResponse::Ok().json(&Response::<()> {
+        status: "success".to_string(),
+        message: format!("Session `{}` revoked", session_id),
+        count: None,
+        data: None,
+    }))
+}
+
+// revoke all the sessions of the current user, except the one of the request
+// #[web::delete("/users/me/sessions/others")]
+pub async fn revoke_my_other_sessions(
+    data: web::types::State<Arc<AppState>>,
+    current_user: CurrentUser,
+) -> Result<web::HttpResponse, AppError> {
+    let revoked =
+        jwt::revoke_other_sessions(&data, current_user.id as usize, &current_user.session_id)
+            .await
+            .map_err(|e| {
+                log::error!("Failed to revoke other sessions: {:?}", e);
+                e
+            })?;
+
+    Ok(web::HttpResponse::Ok().json(&Response::<()> {
+        status: "success".to_string(),
+        message: "Other sessions revoked".to_string(),
+        count: Some(revoked as i64),
+        data: None,
+    }))
+}
+
+// revoke all the sessions of any user, only admins are allowed
+// #[web::delete("/users/sessions")]
+pub async fn revoke_user_sessions(
+    data: web::types::State<Arc<AppState>>,
+    current_user: CurrentUser,
+    query: web::types::Query<UserSessionsQuery>,
+) -> Result<web::HttpResponse, AppError> {
+    current_user.require_admin()?;
+    let user_id = query
+        .id
+        .ok_or_else(|| AppError::BadRequest("User id is required".to_string()))?;
+
+    let revoked = jwt::revoke_user_sessions(&data, user_id as usize)
+        .await
+        .map_err(|e| {
+            log::error!("Failed to revoke all tokens of user {}: {:?}", user_id, e);
+            e
+        })?;
+    log::info!(
+        "admin {} revoked {} sessions of user {}",
+        current_user.id,
+        revoked,
+        user_id
+    );
+
+    Ok(web::HttpResponse::Ok().json(&Response::<()> {
+        status: "success".to_string(),
+        message: format!("All sessions of user `{}` revoked", user_id),
+        count: Some(revoked as i64),
+        data: None,
+    }))
+}
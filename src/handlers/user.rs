@@ -1,36 +1,99 @@
-use dotenvy::dotenv;
-use ntex::http;
 use ntex::web::{self, Error};
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 
 use crate::{
     errors::AppError,
-    handlers::Response,
-    models::user::{self, NewUser, SearchQuery, User, UserLogin},
-    utils::{jwt, jwt::Claims},
+    handlers::{email_verification, Response},
+    middleware::{auth::bearer_token, permission::CurrentUser},
+    models::{
+        mfa,
+        user::{
+            self, AdminUserView, ChangePassword, NewUser, ScopedUserView, SearchQuery, SetPassword,
+            User, UserLogin, UserView,
+        },
+    },
+    repository::login_attempts,
+    utils::jwt,
     AppState,
 };
 
-#[derive(Deserialize, Serialize)]
-#[serde(untagged)] // 可以自动反序列化像 {id: 123} 或 {name: "Jason"} 这样的 JSON 数据
-pub enum UserQuery {
-    Id { id: i32 },
-    Name { name: String },
-}
-
 #[derive(Deserialize, Serialize)]
 pub struct Info {
     id: Option<i32>,
     name: Option<String>,
 }
 
-// create a new user
+#[derive(Deserialize)]
+pub struct UnlockQuery {
+    // id of the user
+    id: Option<i32>,
+}
+
+#[derive(Deserialize)]
+pub struct LogoutQuery {
+    // revoke all the sessions of the user instead of the current one
+    all: Option<bool>,
+}
+
+// create a new user, only admins can create users with any role
 // #[web::post("/user")]
 pub async fn create_user(
+    data: web::types::State<Arc<AppState>>,
+    current_user: CurrentUser,
+    user: web::types::Json<NewUser>,
+) -> Result<web::HttpResponse, AppError> {
+    current_user.require_admin()?;
+
+    let new_user = insert_user(&data, user.into_inner()).await?;
+
+    Ok(
+        web::HttpResponse::Created().json(&Response::<AdminUserView> {
+            status: "success".to_string(),
+            message: format!(
+                "User `{}` with id `{}` created successfully",
+                new_user.name, new_user.id
+            ),
+            count: None,
+            data: Some(new_user.into()),
+        }),
+    )
+}
+
+// register a new user by itself, the role is always the default one
+pub async fn register_user(
     data: web::types::State<Arc<AppState>>,
     user: web::types::Json<NewUser>,
 ) -> Result<web::HttpResponse, AppError> {
+    let new_user = insert_user(&data, registered_fields(user.into_inner())).await?;
+
+    Ok(web::HttpResponse::Created().json(&Response::<UserView> {
+        status: "success".to_string(),
+        message: format!(
+            "User `{}` with id `{}` created successfully, check your email to verify it",
+            new_user.name, new_user.id
+        ),
+        count: None,
+        data: Some(new_user.into()),
+    }))
+}
+
+// the fields a new user sets on registration, the id, role and timestamps are the server's
+fn registered_fields(user: NewUser) -> NewUser {
+    NewUser {
+        id: None,
+        role: None,
+        created_at: None,
+        modified_at: None,
+        deleted_at: None,
+        ..user
+    }
+}
+
+async fn insert_user(
+    data: &web::types::State<Arc<AppState>>,
+    user: NewUser,
+) -> Result<User, AppError> {
     let mut conn = data
         .pool
         .get()
@@ -39,7 +102,7 @@ pub async fn create_user(
 
     // query the user by email to check if it already exists
     let existing_user =
-        web::block(move || user::get_user_by_email(&mut conn, &user.email.as_ref().unwrap()))
+        web::block(move || user::get_user_by_email(&mut conn, user.email.as_ref().unwrap()))
             .await
             .map_err(|e| {
                 log::error!("Failed to get user by email: {:?}", e);
@@ -68,29 +131,35 @@ pub async fn create_user(
         AppError::BadRequest(e.to_string())
     })?;
 
-    Ok(web::HttpResponse::Created().json(&Response::<&User> {
-        status: "success".to_string(),
-        message: format!(
-            "User `{}` with id `{}` created successfully",
-            new_user.name, new_user.id
-        ),
-        count: None,
-        data: Some(&new_user),
-    }))
+    send_verification(data, &new_user).await;
+
+    Ok(new_user)
+}
+
+// send a verification link to the new email of a user.
+// The user is saved anyway, a new link can be asked for if this one fails
+async fn send_verification(data: &AppState, user: &User) {
+    if let Err(e) = email_verification::send_verification(data, user).await {
+        log::error!("Failed to send the verification email: {}", e);
+    }
 }
 
 // user login with email and password
 pub async fn user_login(
     data: web::types::State<Arc<AppState>>,
+    client: jwt::ClientInfo,
     user: web::types::Json<UserLogin>,
 ) -> Result<web::HttpResponse, AppError> {
     // check if email and password are provided
-    if (&user).email.is_empty() || (&user).password.is_empty() {
+    if user.email.is_empty() || user.password.is_empty() {
         return Err(AppError::BadRequest(
             "Email and password are required".to_string(),
         ));
     }
 
+    // the attempt is counted before the password is checked, a locked out account or ip is rejected
+    let attempt = begin_login_attempt(&data, &user.email, &client).await?;
+
     // verify user by email and password from db
     let mut conn = data
         .pool
@@ -103,73 +172,127 @@ pub async fn user_login(
             AppError::Unauthorized
         })?;
 
-    if let Some(user) = user {
-        // if user is verified, generate jwt token
-        let access_claims = Claims::new(&user.name, "pwr.ink");
-        let access_token =
-            jwt::generate_token(jwt::TokenType::AccessToken, &access_claims).unwrap();
-        let refresh_claims = Claims::new(&user.name, "pwr.ink");
-        let refresh_token =
-            jwt::generate_token(jwt::TokenType::RefreshToken, &refresh_claims).unwrap();
-        let token = jwt::Token {
-            access_token,
-            refresh_token,
-        };
+    // if user is not verified, return unauthorized, the failed attempt stays counted
+    let Some(user) = user else {
+        return Err(AppError::Unauthorized);
+    };
 
-        #[derive(Serialize)]
-        struct LoginResponse<'a> {
-            user: &'a User,
-            token: &'a jwt::Token,
-        }
+    // the email must be verified before logging in, when it's required
+    if data.config.require_email_verification && !user.is_email_verified() {
+        finish_login_attempt(attempt, false).await;
+        return Err(AppError::EmailNotVerified);
+    }
 
-        dotenv().ok();
-        let access_token_max_age =
-            std::env::var("ACCESS_TOKEN_MAXAGE").expect("DATABASE_URL must be set");
-        let refresh_token_max_age =
-            std::env::var("REFRESH_TOKEN_MAXAGE").expect("DATABASE_URL must be set");
-        log::info!("access_claims: {:?}", access_claims);
-        log::info!("refresh_claims: {:?}", refresh_claims);
-        // save tokens data to redis with their expire time
-        jwt::save_token_to_redis(
-            &data,
-            access_claims.token_id.as_str(),
-            user.id as usize,
-            access_token_max_age.parse::<u64>().unwrap() * 60,
-        )
-        .await
-        .map_err(|e| {
-            log::error!("Failed to save access_token: {:?}", e);
-            AppError::BadRequest(e.to_string())
-        })?;
-        jwt::save_token_to_redis(
-            &data,
-            refresh_claims.token_id.as_str(),
-            user.id as usize,
-            refresh_token_max_age.parse::<u64>().unwrap() * 60,
-        )
+    // with mfa, the password only gets a challenge token to be exchanged with a TOTP code.
+    // The previous failures are kept until then, so the codes can't be guessed by logging in again
+    let mut conn = data
+        .pool
+        .get()
+        .expect("couldn't get db connection from pool");
+    let user_id = user.id;
+    let mfa_enabled = web::block(move || mfa::is_enabled(&mut conn, user_id))
         .await
         .map_err(|e| {
-            log::error!("Failed to save refresh_token: {:?}", e);
-            AppError::BadRequest(e.to_string())
+            log::error!("Failed to get the mfa of user {}: {:?}", user_id, e);
+            AppError::InternalServerError(e.to_string())
         })?;
+    if mfa_enabled {
+        finish_login_attempt(attempt, false).await;
+        let challenge_token =
+            jwt::generate_mfa_challenge(&data.config, &data.keys, user.id, &jwt::SystemClock)
+                .map_err(|e| {
+                    AppError::InternalServerError(format!("Failed to generate token: {}", e))
+                })?;
 
-        log::info!("token: {:?}", &token);
+        #[derive(Serialize)]
+        struct MfaChallenge {
+            mfa_required: bool,
+            challenge_token: String,
+            // seconds
+            expires_in: u64,
+        }
 
-        Ok(web::HttpResponse::Ok().json(&Response::<LoginResponse> {
+        return Ok(web::HttpResponse::Ok().json(&Response::<MfaChallenge> {
             status: "success".to_string(),
-            message: "User verified".to_string(),
+            message: "MFA code required".to_string(),
             count: None,
-            data: Some(LoginResponse {
-                user: &user,
-                token: &token,
+            data: Some(MfaChallenge {
+                mfa_required: true,
+                challenge_token,
+                expires_in: data.config.mfa_challenge_max_age * 60,
             }),
-        }))
+        }));
+    }
+
+    finish_login_attempt(attempt, true).await;
+    start_session(&data, user, &client).await
+}
+
+/// count a login attempt of the account and the client, it's rejected while either is locked out.
+/// Without redis, or while it's down, logins aren't throttled
+pub async fn begin_login_attempt(
+    data: &AppState,
+    email: &str,
+    client: &jwt::ClientInfo,
+) -> Result<Option<login_attempts::Attempt>, AppError> {
+    let Some(redis) = &data.redis else {
+        return Ok(None);
+    };
+    match login_attempts::begin(redis, &data.config, email, client.ip.as_deref()).await {
+        Ok(attempt) => Ok(Some(attempt)),
+        Err(AppError::TooManyRequests(retry_after)) => Err(AppError::TooManyRequests(retry_after)),
+        Err(e) => {
+            log::error!("Failed to count the login attempt: {}", e);
+            Ok(None)
+        }
+    }
+}
+
+/// give back the attempt of a verified password or code,
+/// the failures of the account are forgotten too once the user is logged in
+pub async fn finish_login_attempt(attempt: Option<login_attempts::Attempt>, logged_in: bool) {
+    let Some(attempt) = attempt else {
+        return;
+    };
+    let finished = if logged_in {
+        attempt.succeed().await
     } else {
-        // if user is not verified, return unauthorized
-        Err(AppError::Unauthorized)
+        attempt.refund().await
+    };
+    if let Err(e) = finished {
+        log::error!("Failed to record the login attempt: {}", e);
     }
 }
 
+/// issue the token pair of a new session to a verified user, the response of a successful login
+pub async fn start_session(
+    data: &AppState,
+    user: User,
+    client: &jwt::ClientInfo,
+) -> Result<web::HttpResponse, AppError> {
+    // if user is verified, generate jwt tokens and save them to redis
+    let token = jwt::issue_tokens(data, &user, client).await.map_err(|e| {
+        log::error!("Failed to issue tokens: {:?}", e);
+        e
+    })?;
+
+    #[derive(Serialize)]
+    struct LoginResponse<'a> {
+        user: UserView,
+        token: &'a jwt::Token,
+    }
+
+    Ok(web::HttpResponse::Ok().json(&Response::<LoginResponse> {
+        status: "success".to_string(),
+        message: "User verified".to_string(),
+        count: None,
+        data: Some(LoginResponse {
+            user: user.into(),
+            token: &token,
+        }),
+    }))
+}
+
 // refresh jwt token
 pub async fn refresh_token(
     data: web::types::State<Arc<AppState>>,
@@ -180,27 +303,14 @@ pub async fn refresh_token(
         token: &'a jwt::Token,
     }
 
-    // get headers from request
-    let headers = req
-        .headers()
-        // get the authorization header from the request, which contains the jwt token.
-        // This is the same as `.get("Authorization")`
-        .get(http::header::AUTHORIZATION)
-        .unwrap()
-        .to_str()
-        .unwrap();
-
-    let refresh_token = headers.replace("Bearer ", "");
-    log::info!(
-        "refresh_token in refresh token handler: {:?}",
-        &refresh_token
-    );
+    // get the refresh token from the authorization header of the request
+    let refresh_token = bearer_token(req.headers()).ok_or(AppError::Unauthorized)?;
 
-    let token = jwt::refresh_token(&data, &refresh_token.as_str())
+    let token = jwt::refresh_token(&data, refresh_token)
         .await
         .map_err(|e| {
             log::error!("Failed to refresh token: {:?}", e);
-            AppError::Unauthorized
+            e
         })?;
 
     Ok(web::HttpResponse::Ok().json(&Response::<TokenResponse> {
@@ -211,17 +321,64 @@ pub async fn refresh_token(
     }))
 }
 
+// user logout, revoke the access token and the refresh token issued with it.
+// With `?all=true`, all the sessions of the user are revoked.
+pub async fn user_logout(
+    data: web::types::State<Arc<AppState>>,
+    web::types::Query(query): web::types::Query<LogoutQuery>,
+    req: ntex::web::HttpRequest,
+) -> Result<web::HttpResponse, AppError> {
+    let access_token = bearer_token(req.headers()).ok_or(AppError::Unauthorized)?;
+
+    // the access token may already be expired, logout should still work with it
+    let principal = jwt::decode_expired_token(
+        &data.config,
+        &data.keys,
+        jwt::TokenType::AccessToken,
+        access_token,
+    )
+    .and_then(jwt::Principal::try_from)
+    .map_err(|e| {
+        log::error!("Invalid access token: {:?}", e);
+        AppError::Unauthorized
+    })?;
+
+    let user_id = jwt::revoke_session(&data, principal.user_id as usize, &principal.session_id)
+        .await
+        .map_err(|e| {
+            log::error!("Failed to revoke tokens: {:?}", e);
+            e
+        })?;
+
+    if query.all.unwrap_or(false) {
+        // the tokens are already gone, there is no way to know whose sessions to revoke
+        let user_id = user_id.ok_or(AppError::Unauthorized)?;
+        jwt::revoke_user_sessions(&data, user_id)
+            .await
+            .map_err(|e| {
+                log::error!("Failed to revoke all tokens of user {}: {:?}", user_id, e);
+                e
+            })?;
+    }
+
+    Ok(web::HttpResponse::Ok().json(&Response::<()> {
+        status: "success".to_string(),
+        message: "User logged out".to_string(),
+        count: None,
+        data: None,
+    }))
+}
+
 /// get a user by id or name
 /// extract path info from "users?id={id}&name={name}" url
 /// {id} - deserializes to a i32
 /// {name} -  - deserializes to a String
-pub async fn get_user_by_id_or_name<T>(
+/// regular users can only get themselves by id, only admins can look up other users
+pub async fn get_user_by_id_or_name(
     data: web::types::State<Arc<AppState>>,
+    current_user: CurrentUser,
     web::types::Query(info): web::types::Query<Info>,
-) -> Result<web::HttpResponse, AppError>
-where
-    T: Serialize + Send,
-{
+) -> Result<web::HttpResponse, AppError> {
     let mut conn = data
         .pool
         .get()
@@ -230,6 +387,7 @@ where
     let Info { id, name } = info;
 
     let user_result = if let Some(id) = id {
+        current_user.require_self_or_admin(id)?;
         web::block(move || user::get_users_by_id(&mut conn, id))
             .await
             .map_err(|e| {
@@ -237,6 +395,7 @@ where
                 AppError::BadRequest(e.to_string())
             })?
     } else if let Some(name) = name {
+        current_user.require_admin()?;
         web::block(move || user::get_users_by_name(&mut conn, &name))
             .await
             .map_err(|e| {
@@ -249,33 +408,39 @@ where
         ));
     };
 
-    Ok(web::HttpResponse::Ok().json(&Response::<Vec<User>> {
-        status: "success".to_string(),
-        message: "User found".to_string(),
-        count: None,
-        data: Some(user_result),
-    }))
+    Ok(
+        web::HttpResponse::Ok().json(&Response::<Vec<ScopedUserView>> {
+            status: "success".to_string(),
+            message: "User found".to_string(),
+            count: None,
+            data: Some(
+                user_result
+                    .into_iter()
+                    .map(|user| ScopedUserView::new(current_user.role, user))
+                    .collect(),
+            ),
+        }),
+    )
 }
 
-// search users by name or email with pagination and sorting
+// search users by name or email with pagination and sorting, only admins can search users
 // #[web::post("/users/search")]
 pub async fn search_users(
     data: web::types::State<Arc<AppState>>,
-    query: web::types::Json<SearchQuery>,
-    req: ntex::web::HttpRequest,
+    current_user: CurrentUser,
+    query: web::types::Json<serde_json::Value>,
 ) -> Result<web::HttpResponse, AppError> {
-    // get headers from request
-    let headers = req
-        .headers()
-        // get the authorization header from the request, which contains the jwt token.
-        // This is the same as `.get("Authorization")`
-        .get(http::header::AUTHORIZATION)
-        .unwrap()
-        .to_str()
-        .unwrap();
-
-    let token = headers.replace("Bearer ", "");
-    log::info!("token: {:?}", &token);
+    current_user.require_admin()?;
+    // deserialize the query here instead of by the extractor, so that invalid sort columns
+    // or directions are reported with the allowed values in the standard response
+    let query = serde_json::from_value::<SearchQuery>(query.into_inner())
+        .map_err(|e| AppError::BadRequest(format!("Invalid search query: {}", e)))?;
+    log::info!(
+        "user {} searches users with token {} of session {}",
+        current_user.id,
+        current_user.token_id,
+        current_user.session_id
+    );
 
     let mut conn = data
         .pool
@@ -285,8 +450,7 @@ pub async fn search_users(
         user::search_users(
             &mut conn,
             &query.search_term,
-            &query.sort_by,
-            &query.order_by,
+            &query.sort_specs(),
             query.page,
             query.page_size,
         )
@@ -298,7 +462,7 @@ pub async fn search_users(
     })?;
 
     // map_or_else 第一个闭包参数是没有元素时的处理，第二个闭包参数是有元素时的处理
-    let message = users.iter().next().map_or_else(
+    let message = users.first().map_or_else(
         || "No user found".to_string(),
         |_| match count {
             0 => "No users found".to_string(),
@@ -307,79 +471,553 @@ pub async fn search_users(
         },
     );
 
-    Ok(web::HttpResponse::Ok().json(&Response::<Vec<User>> {
-        status: "success".to_string(),
-        message,
-        count: Some(count),
-        data: Some(users),
-    }))
+    Ok(
+        web::HttpResponse::Ok().json(&Response::<Vec<AdminUserView>> {
+            status: "success".to_string(),
+            message,
+            count: Some(count),
+            data: Some(users.into_iter().map(AdminUserView::from).collect()),
+        }),
+    )
 }
 
 // update a user by id
+// regular users can only update their own record, only admins can change the role of a user
 pub async fn update_user_by_id(
     data: web::types::State<Arc<AppState>>,
+    current_user: CurrentUser,
     user: web::types::Json<NewUser>,
 ) -> Result<web::HttpResponse, Error> {
+    let id = user
+        .id
+        .ok_or_else(|| AppError::BadRequest("User id is required".to_string()))?;
+    current_user.require_self_or_admin(id)?;
+    if user.role.is_some() {
+        current_user.require_admin()?;
+    }
+    // the password would be saved in plaintext by the generic update
+    if user.password.is_some() {
+        return Err(AppError::BadRequest(
+            "Use /users/me/password or /users/password to change the password".to_string(),
+        )
+        .into());
+    }
+
     let mut conn = data
         .pool
         .get()
         .expect("couldn't get db connection from pool");
 
-    let updated_user = web::block(move || match user.id {
-        Some(id) => user::update_user_by_id(&mut conn, id, user.into_inner()),
-        None => Err(diesel::result::Error::DeserializationError(
-            "User id is required".into(),
-        )),
-    })
-    .await
-    .map_err(|e| {
-        log::error!("Failed to update user by id: {:?}", e);
-        web::Error::from(e)
-    })?;
+    let user = editable_fields(&current_user, user.into_inner());
+    let (updated_user, email_changed) =
+        web::block(move || user::update_user_by_id(&mut conn, id, user))
+            .await
+            .map_err(|e| {
+                log::error!("Failed to update user by id: {:?}", e);
+                web::Error::from(e)
+            })?;
+    if email_changed {
+        send_verification(&data, &updated_user).await;
+    }
 
-    Ok(web::HttpResponse::Ok().json(&Response::<&User> {
+    Ok(web::HttpResponse::Ok().json(&Response::<ScopedUserView> {
         status: "success".to_string(),
         message: format!(
             "User `{}` with id `{}` updated successfully",
             updated_user.name, updated_user.id
         ),
         count: None,
-        data: Some(&updated_user),
+        data: Some(ScopedUserView::new(current_user.role, updated_user)),
     }))
 }
 
+// the fields of an update the caller may change, only admins rewrite the history of a user.
+// Regular users delete themselves with `DELETE /users/me`, which revokes their sessions too
+fn editable_fields(current_user: &CurrentUser, user: NewUser) -> NewUser {
+    if current_user.is_admin() {
+        return user;
+    }
+    NewUser {
+        created_at: None,
+        deleted_at: None,
+        ..user
+    }
+}
+
 // delete a user by id, soft delete by setting deleted_at
-pub async fn delete_user_by_id<T>(
+// regular users can only delete themselves, only admins can delete other users
+pub async fn delete_user_by_id(
     data: web::types::State<Arc<AppState>>,
+    current_user: CurrentUser,
     web::types::Query(info): web::types::Query<Info>,
-) -> Result<web::HttpResponse, Error>
-where
-    T: Serialize + Send,
-{
+) -> Result<web::HttpResponse, Error> {
+    let id = info
+        .id
+        .ok_or_else(|| AppError::BadRequest("User id is required".to_string()))?;
+    current_user.require_self_or_admin(id)?;
+
+    let mut conn = data
+        .pool
+        .get()
+        .expect("couldn't get db connection from pool");
+
+    let deleted_user = web::block(move || user::delete_user_by_id(&mut conn, id))
+        .await
+        .map_err(|e| {
+            log::error!("Failed to delete user by id: {:?}", e);
+            web::Error::from(e)
+        })?;
+
+    Ok(web::HttpResponse::Ok().json(&Response::<ScopedUserView> {
+        status: "success".to_string(),
+        message: format!(
+            "User `{}` with id `{}` deleted successfully",
+            deleted_user.name, deleted_user.id
+        ),
+        count: None,
+        data: Some(ScopedUserView::new(current_user.role, deleted_user)),
+    }))
+}
+
+// get the profile of the current user
+// #[web::get("/users/me")]
+pub async fn get_me(
+    data: web::types::State<Arc<AppState>>,
+    current_user: CurrentUser,
+) -> Result<web::HttpResponse, AppError> {
+    let mut conn = data
+        .pool
+        .get()
+        .expect("couldn't get db connection from pool");
+
+    let user_id = current_user.id;
+    let users = web::block(move || user::get_users_by_id(&mut conn, user_id))
+        .await
+        .map_err(|e| {
+            log::error!("Failed to get user: {:?}", e);
+            AppError::BadRequest(e.to_string())
+        })?;
+    let user = users.into_iter().next().ok_or(AppError::NotFound)?;
+
+    Ok(web::HttpResponse::Ok().json(&Response::<UserView> {
+        status: "success".to_string(),
+        message: "User found".to_string(),
+        count: None,
+        data: Some(user.into()),
+    }))
+}
+
+// update the profile of the current user
+// the password is changed by `change_my_password`, and only admins can change their role
+// #[web::patch("/users/me")]
+pub async fn update_me(
+    data: web::types::State<Arc<AppState>>,
+    current_user: CurrentUser,
+    user: web::types::Json<NewUser>,
+) -> Result<web::HttpResponse, AppError> {
+    if user.password.is_some() {
+        return Err(AppError::BadRequest(
+            "Use /users/me/password to change the password".to_string(),
+        ));
+    }
+    if user.role.is_some() {
+        current_user.require_admin()?;
+    }
+
     let mut conn = data
         .pool
         .get()
         .expect("couldn't get db connection from pool");
 
-    let deleted_user = web::block(move || match info.id {
-        Some(id) => user::delete_user_by_id(&mut conn, id),
-        _ => Err(diesel::result::Error::DeserializationError(
-            "User id is required".into(),
-        )),
+    let user_id = current_user.id;
+    let user = NewUser {
+        id: None,
+        created_at: None,
+        deleted_at: None,
+        ..user.into_inner()
+    };
+    let (updated_user, email_changed) =
+        web::block(move || user::update_user_by_id(&mut conn, user_id, user))
+            .await
+            .map_err(|e| {
+                log::error!("Failed to update user by id: {:?}", e);
+                AppError::BadRequest(e.to_string())
+            })?;
+    if email_changed {
+        send_verification(&data, &updated_user).await;
+    }
+
+    Ok(web::HttpResponse::Ok().json(&Response::<UserView> {
+        status: "success".to_string(),
+        message: format!(
+            "User `{}` with id `{}` updated successfully",
+            updated_user.name, updated_user.id
+        ),
+        count: None,
+        data: Some(updated_user.into()),
+    }))
+}
+
+// delete the current user, soft delete by setting deleted_at, and revoke all its sessions
+// #[web::delete("/users/me")]
+pub async fn delete_me(
+    data: web::types::State<Arc<AppState>>,
+    current_user: CurrentUser,
+) -> Result<web::HttpResponse, AppError> {
+    let mut conn = data
+        .pool
+        .get()
+        .expect("couldn't get db connection from pool");
+
+    let user_id = current_user.id;
+    let deleted_user = web::block(move || user::delete_user_by_id(&mut conn, user_id))
+        .await
+        .map_err(|e| {
+            log::error!("Failed to delete user by id: {:?}", e);
+            AppError::BadRequest(e.to_string())
+        })?;
+
+    jwt::revoke_user_sessions(&data, user_id as usize)
+        .await
+        .map_err(|e| {
+            log::error!("Failed to revoke all tokens of user {}: {:?}", user_id, e);
+            e
+        })?;
+
+    Ok(web::HttpResponse::Ok().json(&Response::<UserView> {
+        status: "success".to_string(),
+        message: format!(
+            "User `{}` with id `{}` deleted successfully",
+            deleted_user.name, deleted_user.id
+        ),
+        count: None,
+        data: Some(deleted_user.into()),
+    }))
+}
+
+// change the password of the current user, the old password must be provided
+// #[web::post("/users/me/password")]
+pub async fn change_my_password(
+    data: web::types::State<Arc<AppState>>,
+    current_user: CurrentUser,
+    body: web::types::Json<ChangePassword>,
+) -> Result<web::HttpResponse, AppError> {
+    if body.old_password.is_empty() || body.new_password.is_empty() {
+        return Err(AppError::BadRequest(
+            "Old password and new password are required".to_string(),
+        ));
+    }
+
+    let mut conn = data
+        .pool
+        .get()
+        .expect("couldn't get db connection from pool");
+
+    let user_id = current_user.id;
+    let updated_user = web::block(move || {
+        user::change_password(&mut conn, user_id, &body.old_password, &body.new_password)
     })
     .await
     .map_err(|e| {
-        log::error!("Failed to delete user by id: {:?}", e);
-        web::Error::from(e)
-    })?;
+        log::error!("Failed to change password: {:?}", e);
+        AppError::BadRequest(e.to_string())
+    })?
+    .ok_or_else(|| AppError::BadRequest("Old password is incorrect".to_string()))?;
+
+    revoke_sessions_after_password_change(&data, user_id).await?;
 
-    Ok(web::HttpResponse::Ok().json(&Response::<&User> {
+    Ok(web::HttpResponse::Ok().json(&Response::<()> {
         status: "success".to_string(),
         message: format!(
-            "User `{}` with id `{}` deleted successfully",
-            deleted_user.name, deleted_user.id
+            "Password of user `{}` with id `{}` changed successfully, please log in again",
+            updated_user.name, updated_user.id
+        ),
+        count: None,
+        data: None,
+    }))
+}
+
+// the sessions created with the old password are no longer trusted, the user must log in again
+async fn revoke_sessions_after_password_change(
+    data: &AppState,
+    user_id: i32,
+) -> Result<(), AppError> {
+    jwt::revoke_user_sessions(data, user_id as usize)
+        .await
+        .map(|_| ())
+        .map_err(|e| {
+            log::error!("Failed to revoke all tokens of user {}: {:?}", user_id, e);
+            e
+        })
+}
+
+// set the password of any user without the old one, only admins can do it
+// #[web::post("/users/password")]
+pub async fn set_user_password(
+    data: web::types::State<Arc<AppState>>,
+    current_user: CurrentUser,
+    body: web::types::Json<SetPassword>,
+) -> Result<web::HttpResponse, AppError> {
+    current_user.require_admin()?;
+    if body.new_password.is_empty() {
+        return Err(AppError::BadRequest("New password is required".to_string()));
+    }
+
+    let mut conn = data
+        .pool
+        .get()
+        .expect("couldn't get db connection from pool");
+
+    let user_id = body.id;
+    let updated_user =
+        web::block(move || user::set_password(&mut conn, body.id, &body.new_password))
+            .await
+            .map_err(|e| {
+                log::error!("Failed to set password: {:?}", e);
+                match e {
+                    web::error::BlockingError::Error(diesel::result::Error::NotFound) => {
+                        AppError::NotFound
+                    }
+                    e => AppError::BadRequest(e.to_string()),
+                }
+            })?;
+
+    revoke_sessions_after_password_change(&data, user_id).await?;
+
+    Ok(web::HttpResponse::Ok().json(&Response::<()> {
+        status: "success".to_string(),
+        message: format!(
+            "Password of user `{}` with id `{}` changed successfully",
+            updated_user.name, updated_user.id
         ),
         count: None,
-        data: Some(&deleted_user),
+        data: None,
     }))
 }
+
+// unlock the login of a user locked out after failed attempts, only admins can do it
+// #[web::delete("/users/lockout")]
+pub async fn unlock_user(
+    data: web::types::State<Arc<AppState>>,
+    current_user: CurrentUser,
+    web::types::Query(query): web::types::Query<UnlockQuery>,
+) -> Result<web::HttpResponse, AppError> {
+    current_user.require_admin()?;
+    let user_id = query
+        .id
+        .ok_or_else(|| AppError::BadRequest("User id is required".to_string()))?;
+    let redis = data
+        .redis
+        .as_ref()
+        .ok_or_else(|| AppError::BadRequest("Logins aren't throttled without Redis".to_string()))?;
+
+    let mut conn = data
+        .pool
+        .get()
+        .expect("couldn't get db connection from pool");
+    let users = web::block(move || user::get_users_by_id(&mut conn, user_id))
+        .await
+        .map_err(|e| {
+            log::error!("Failed to get user: {:?}", e);
+            AppError::BadRequest(e.to_string())
+        })?;
+    let user = users.into_iter().next().ok_or(AppError::NotFound)?;
+
+    login_attempts::reset(redis, &user.email).await?;
+    log::info!(
+        "admin {} unlocked the login of user {}",
+        current_user.id,
+        user.id
+    );
+
+    Ok(web::HttpResponse::Ok().json(&Response::<()> {
+        status: "success".to_string(),
+        message: format!("Login of user `{}` unlocked", user.id),
+        count: None,
+        data: None,
+    }))
+}
+
+#[cfg(test)]
+#[test]
+fn test_registered_fields() {
+    let user: NewUser = serde_json::from_str(
+        r#"{"id": 1, "name": "elton", "role": "admin", "created_at": "2020-01-01T00:00:00Z", "modified_at": "2020-01-01T00:00:00Z", "deleted_at": "2020-01-01T00:00:00Z"}"#,
+    )
+    .unwrap();
+    let user = registered_fields(user);
+    assert_eq!(user.name.as_deref(), Some("elton"));
+    assert!(user.id.is_none() && user.role.is_none());
+    assert!(user.created_at.is_none() && user.modified_at.is_none() && user.deleted_at.is_none());
+}
+
+#[cfg(test)]
+#[test]
+fn test_editable_fields() {
+    use crate::models::user::Role;
+
+    let current_user = |role| CurrentUser {
+        id: 2,
+        role,
+        session_id: "01HSJARKXDAH23Z8SF6ZY475TS".to_string(),
+        token_id: "01HSJARKXDAH23Z8SF6ZY475TV".to_string(),
+    };
+    let update: NewUser = serde_json::from_str(
+        r#"{"id": 2, "name": "elton", "created_at": "2020-01-01T00:00:00Z", "deleted_at": "2020-01-01T00:00:00Z"}"#,
+    )
+    .unwrap();
+
+    let user = editable_fields(&current_user(Role::User), update.clone());
+    assert_eq!(user.name.as_deref(), Some("elton"));
+    assert!(user.created_at.is_none());
+    assert!(user.deleted_at.is_none());
+
+    let admin = editable_fields(&current_user(Role::Admin), update);
+    assert!(admin.created_at.is_some());
+    assert!(admin.deleted_at.is_some());
+}
+
+// a user with a session started by each of the returned token pairs
+#[cfg(test)]
+async fn test_sessions(data: &AppState, sessions: usize) -> Vec<jwt::Token> {
+    let user = User {
+        id: 2,
+        name: "elton".to_string(),
+        email: "elton@pwr.ink".to_string(),
+        avatar: None,
+        password: String::new(),
+        role: user::Role::User,
+        created_at: None,
+        modified_at: None,
+        deleted_at: None,
+        email_verified_at: None,
+    };
+    let mut tokens = Vec::new();
+    for _ in 0..sessions {
+        let token = jwt::issue_tokens(data, &user, &jwt::ClientInfo::default())
+            .await
+            .unwrap();
+        tokens.push(token);
+    }
+    tokens
+}
+
+#[cfg(test)]
+fn session_id(data: &AppState, token: &jwt::Token) -> String {
+    jwt::decode_expired_token(
+        &data.config,
+        &data.keys,
+        jwt::TokenType::AccessToken,
+        &token.access_token,
+    )
+    .and_then(jwt::Principal::try_from)
+    .unwrap()
+    .session_id
+}
+
+#[cfg(test)]
+fn logout_request(uri: &str, token: &jwt::Token) -> ntex::http::Request {
+    web::test::TestRequest::post()
+        .uri(uri)
+        .header(
+            ntex::http::header::AUTHORIZATION,
+            format!("Bearer {}", token.access_token),
+        )
+        .to_request()
+}
+
+#[cfg(test)]
+#[ntex::test]
+async fn test_user_logout() {
+    let data = Arc::new(crate::test_state());
+    let tokens = test_sessions(&data, 2).await;
+    let app = web::test::init_service(
+        web::App::new()
+            .state(data.clone())
+            .route("/auth/logout", web::post().to(user_logout)),
+    )
+    .await;
+
+    // only the session of the token is revoked
+    let res = web::test::call_service(&app, logout_request("/auth/logout", &tokens[0])).await;
+    assert_eq!(res.status(), ntex::http::StatusCode::OK);
+    let sessions = jwt::list_sessions(&data, 2).await.unwrap();
+    assert_eq!(sessions.len(), 1);
+    assert_eq!(sessions[0].id, session_id(&data, &tokens[1]));
+}
+
+#[cfg(test)]
+#[ntex::test]
+async fn test_user_logout_all() {
+    let data = Arc::new(crate::test_state());
+    let tokens = test_sessions(&data, 3).await;
+    let app = web::test::init_service(
+        web::App::new()
+            .state(data.clone())
+            .route("/auth/logout", web::post().to(user_logout)),
+    )
+    .await;
+
+    let res =
+        web::test::call_service(&app, logout_request("/auth/logout?all=true", &tokens[0])).await;
+    assert_eq!(res.status(), ntex::http::StatusCode::OK);
+    assert!(jwt::list_sessions(&data, 2).await.unwrap().is_empty());
+
+    // the sessions are gone, there is no user to log out everywhere
+    let res =
+        web::test::call_service(&app, logout_request("/auth/logout?all=true", &tokens[1])).await;
+    assert_eq!(res.status(), ntex::http::StatusCode::UNAUTHORIZED);
+}
+
+#[cfg(test)]
+#[ntex::test]
+async fn test_revoke_sessions_after_password_change() {
+    let data = crate::test_state();
+    test_sessions(&data, 2).await;
+    assert_eq!(jwt::list_sessions(&data, 2).await.unwrap().len(), 2);
+
+    revoke_sessions_after_password_change(&data, 2)
+        .await
+        .unwrap();
+    assert!(jwt::list_sessions(&data, 2).await.unwrap().is_empty());
+}
+
+#[cfg(test)]
+#[ntex::test]
+async fn test_update_plaintext_password() {
+    use crate::models::user::Role;
+    use ntex::service::fn_service;
+    use ntex::web::{DefaultError, WebRequest};
+
+    // the auth middleware would resolve the user from the access token
+    let app = web::test::init_service(
+        web::App::new()
+            .state(Arc::new(crate::test_state()))
+            .filter(fn_service(|req: WebRequest<DefaultError>| async move {
+                req.extensions_mut().insert(CurrentUser {
+                    id: 2,
+                    role: Role::Admin,
+                    session_id: "01HSJARKXDAH23Z8SF6ZY475TS".to_string(),
+                    token_id: "01HSJARKXDAH23Z8SF6ZY475TV".to_string(),
+                });
+                Ok(req)
+            }))
+            .route("/users", web::put().to(update_user_by_id))
+            .route("/users/me", web::patch().to(update_me)),
+    )
+    .await;
+
+    // the password isn't saved as it's given, it must be changed by the password endpoints
+    for req in [
+        web::test::TestRequest::put().uri("/users"),
+        web::test::TestRequest::patch().uri("/users/me"),
+    ] {
+        let req = req
+            .set_json(&serde_json::json!({"id": 2, "password": "plaintext"}))
+            .to_request();
+        let res = web::test::call_service(&app, req).await;
+        assert_eq!(res.status(), ntex::http::StatusCode::BAD_REQUEST);
+        let body = web::test::read_body(res).await;
+        assert!(String::from_utf8_lossy(&body).contains("to change the password"));
+    }
+}
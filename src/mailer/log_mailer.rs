@@ -0,0 +1,16 @@
+use async_trait::async_trait;
+
+use super::{Email, Mailer};
+use crate::errors::AppError;
+
+/// Writes the emails to the log instead of sending them, for local development.
+/// The emails hold one-time tokens, the log must not be shared.
+pub struct LogMailer;
+
+#[async_trait(?Send)]
+impl Mailer for LogMailer {
+    async fn send(&self, email: Email) -> Result<(), AppError> {
+        log::info!("email to {}: {}\n{}", email.to, email.subject, email.body);
+        Ok(())
+    }
+}
@@ -0,0 +1,51 @@
+use async_trait::async_trait;
+use serde::Serialize;
+use std::sync::Arc;
+
+use crate::{
+    config::{Config, MailerKind},
+    errors::AppError,
+};
+
+mod log_mailer;
+mod webhook_mailer;
+
+pub use log_mailer::LogMailer;
+pub use webhook_mailer::WebhookMailer;
+
+/// A plain text email to a user.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+/// How emails are sent, picked by `MAILER`.
+/// The emails are sent in the background, the requests don't wait for the mail provider.
+#[async_trait(?Send)]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, email: Email) -> Result<(), AppError>;
+}
+
+/// set up the mailer configured by `MAILER`
+pub fn new(config: &Config) -> Arc<dyn Mailer> {
+    match config.mailer {
+        MailerKind::Log => Arc::new(LogMailer),
+        MailerKind::Webhook => Arc::new(WebhookMailer::new(
+            &config.mailer_webhook_url,
+            &config.mail_from,
+        )),
+    }
+}
+
+/// send an email without waiting for it, failures are only logged
+pub fn send_later(mailer: &Arc<dyn Mailer>, email: Email) {
+    let mailer = mailer.clone();
+    ntex::rt::spawn(async move {
+        let to = email.to.clone();
+        if let Err(e) = mailer.send(email).await {
+            log::error!("Failed to send an email to {}: {}", to, e);
+        }
+    });
+}
@@ -0,0 +1,58 @@
+use async_trait::async_trait;
+use ntex::http::client::Client;
+use serde::Serialize;
+
+use super::{Email, Mailer};
+use crate::errors::AppError;
+
+// the seconds to wait for the webhook
+const TIMEOUT: u64 = 10;
+
+/// Posts the emails as JSON to a webhook, e.g. a relay in front of the mail provider.
+/// Any 2xx answer means the email was accepted.
+pub struct WebhookMailer {
+    url: String,
+    from: String,
+}
+
+#[derive(Serialize)]
+struct Message<'a> {
+    from: &'a str,
+    #[serde(flatten)]
+    email: &'a Email,
+}
+
+impl WebhookMailer {
+    pub fn new(url: &str, from: &str) -> Self {
+        WebhookMailer {
+            url: url.to_string(),
+            from: from.to_string(),
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl Mailer for WebhookMailer {
+    async fn send(&self, email: Email) -> Result<(), AppError> {
+        // the client is bound to the worker thread, it's created for each email
+        let response = Client::new()
+            .post(&self.url)
+            .timeout(ntex::time::Seconds(TIMEOUT as u16))
+            .send_json(&Message {
+                from: &self.from,
+                email: &email,
+            })
+            .await
+            .map_err(|e| {
+                log::error!("Failed to call the mailer webhook: {}", e);
+                AppError::ServiceUnavailable
+            })?;
+
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            log::error!("The mailer webhook answered {}", response.status());
+            Err(AppError::ServiceUnavailable)
+        }
+    }
+}
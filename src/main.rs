@@ -1,7 +1,9 @@
 #![recursion_limit = "256"]
 
+mod config;
 mod errors;
 mod handlers;
+mod mailer;
 mod middleware;
 mod models;
 mod repository;
@@ -13,8 +15,17 @@ use ntex_cors::Cors;
 use std::sync::Arc;
 
 pub struct AppState {
+    config: config::Config,
+    keys: utils::keys::KeyStore,
     pool: repository::database::DbPool,
-    redis_client: redis::Client,
+    // the sessions and their token ids, shared by all the workers
+    tokens: Arc<dyn repository::token_store::TokenStore>,
+    // the same store when the stateless fallback is enabled, it verifies tokens during outages
+    fallback: Option<Arc<repository::token_store::FallbackTokenStore>>,
+    // shared by all the workers, it reconnects automatically. Logins and requests are throttled with it
+    redis: Option<redis::aio::ConnectionManager>,
+    // sends the password reset and email verification links
+    mailer: Arc<dyn mailer::Mailer>,
 }
 
 #[ntex::main]
@@ -23,15 +34,32 @@ async fn main() -> std::io::Result<()> {
     std::env::set_var("RUST_LOG", "ntex=info,diesel=debug");
     env_logger::init();
 
-    // load environment variables from os
+    // load environment variables from os and the .env file
     dotenv().ok();
-    let app_port = (std::env::var("SERVER_PORT").ok())
-        .unwrap_or("8000".to_string())
-        .parse::<u16>()
-        .unwrap();
+    // load the configuration once, all the problems are reported together
+    let config = match config::Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            for error in &e.0 {
+                log::error!("🔥 Configuration error: {}", error);
+            }
+            std::process::exit(1);
+        }
+    };
+    let app_port = config.server_port;
+    // parse the jwt keys once, instead of on every request
+    let keys = match utils::keys::KeyStore::from_config(&config) {
+        Ok(keys) => keys,
+        Err(e) => {
+            for error in &e.0 {
+                log::error!("🔥 Configuration error: {}", error);
+            }
+            std::process::exit(1);
+        }
+    };
 
     // set up database connection pool
-    let pool = match repository::database::new() {
+    let pool = match repository::database::new(&config.database_url) {
         Ok(pool) => {
             log::info!("✅ Connection to the database is successful!");
             pool
@@ -42,25 +70,65 @@ async fn main() -> std::io::Result<()> {
         }
     };
 
-    // set up redis connection
-    let redis_client = match repository::redis::new() {
-        Ok(client) => {
-            log::info!("✅ Connection to the redis is successful!");
-            client
+    // set up redis connection, it's optional unless it holds the sessions
+    let redis = if config.redis_url.is_empty() {
+        log::warn!("Redis isn't configured, logins and requests aren't throttled");
+        None
+    } else {
+        match repository::redis::new(&config.redis_url).await {
+            Ok(client) => {
+                log::info!("✅ Connection to the redis is successful!");
+                Some(client)
+            }
+            Err(e) => {
+                log::error!("🔥 Error connecting to Redis: {}", e);
+                std::process::exit(1);
+            }
         }
+    };
+
+    let tokens = match repository::token_store::new(&config, &pool, redis.clone()) {
+        Ok(tokens) => tokens,
         Err(e) => {
-            log::error!("🔥 Error connecting to Redis: {}", e);
+            for error in &e.0 {
+                log::error!("🔥 Configuration error: {}", error);
+            }
             std::process::exit(1);
         }
     };
+    log::info!("✅ Token store is ready: {:?}", config.token_store);
+    if config.token_store == config::TokenStoreKind::Memory {
+        log::warn!("Sessions are kept in memory, they are lost on restart");
+    }
+    let fallback = config.stateless_fallback.then(|| {
+        Arc::new(repository::token_store::FallbackTokenStore::new(
+            tokens.clone(),
+            &config,
+        ))
+    });
+    let tokens = match &fallback {
+        Some(fallback) => fallback.clone(),
+        None => tokens,
+    };
+
+    let mailer = mailer::new(&config);
+    log::info!("✅ Mailer is ready: {:?}", config.mailer);
+    if config.mailer == config::MailerKind::Log {
+        log::warn!("Emails are written to the log, they hold one-time tokens");
+    }
 
     // web::HttpServer can be shutdown gracefully.
     web::HttpServer::new(move || {
         web::App::new()
             // set up DB pool to be used with web::State<Pool> extractor
             .state(Arc::new(AppState {
+                config: config.clone(),
+                keys: keys.clone(),
                 pool: pool.clone(),
-                redis_client: redis_client.clone(),
+                tokens: tokens.clone(),
+                fallback: fallback.clone(),
+                redis: redis.clone(),
+                mailer: mailer.clone(),
             }))
             // enable logger
             .wrap(web::middleware::Logger::default())
@@ -73,6 +141,8 @@ async fn main() -> std::io::Result<()> {
             // enable Compression, A response's Content-Encoding header defaults to ContentEncoding::Auto, which performs automatic content compression negotiation based on the request's Accept-Encoding header.
             // should add "compress" feature to the Cargo.toml
             .wrap(web::middleware::Compress::default())
+            // inside the auth middleware, so it knows the user of the request
+            .wrap(middleware::rate_limit::RateLimit)
             .wrap(middleware::auth::Auth)
             .configure(handlers::config)
     })
@@ -80,3 +150,23 @@ async fn main() -> std::io::Result<()> {
     .run()
     .await
 }
+
+// the state of a server without database or redis, the sessions are kept in memory
+#[cfg(test)]
+fn test_state() -> AppState {
+    use diesel::r2d2::{ConnectionManager, Pool};
+
+    let config = config::test_config();
+    AppState {
+        keys: utils::keys::KeyStore::test_keys(),
+        // no database listens there, the connections fail fast when they are used
+        pool: Pool::builder()
+            .connection_timeout(std::time::Duration::from_millis(100))
+            .build_unchecked(ConnectionManager::new("postgres://127.0.0.1:1/pwr")),
+        tokens: Arc::new(repository::token_store::MemoryTokenStore::default()),
+        fallback: None,
+        redis: None,
+        mailer: mailer::new(&config),
+        config,
+    }
+}
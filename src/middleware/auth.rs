@@ -3,10 +3,12 @@ use ntex::service::{Middleware, Service, ServiceCtx};
 use ntex::web::{Error, ErrorRenderer, WebRequest, WebResponse};
 use ntex::{http, web};
 
+use std::sync::Arc;
+
 use crate::errors::AppError;
 use crate::handlers::Response;
-use crate::repository;
-use crate::utils::jwt;
+use crate::middleware::permission::CurrentUser;
+use crate::AppState;
 
 // There are two steps in middleware processing.
 // 1. Middleware initialization, middleware factory gets called with
@@ -48,13 +50,25 @@ where
             // Note: preflight request is a request with the OPTIONS method
             Method::OPTIONS => {
                 let res = ctx.call(&self.service, req).await?;
-                return Ok(add_cors_header(res, "*"));
+                Ok(add_cors_header(res, "*"))
             }
             _ => {
-                // skip the auth check, if the request is for the refresh_token endpoint
+                // skip the auth check of the public endpoints and of those checking their own credentials,
+                // a stale token sent along mustn't block the login or the recovery of an account
                 match req.path() {
-                    "/api/v1/auth/refresh_token" | "/api/v1/health" => {
-                        log::info!("enter refresh_token endpoint");
+                    "/api/v1/auth/login"
+                    | "/api/v1/auth/mfa"
+                    | "/api/v1/auth/password/forgot"
+                    | "/api/v1/auth/password/reset"
+                    | "/api/v1/auth/register"
+                    | "/api/v1/auth/verify-email"
+                    | "/api/v1/auth/verify-email/resend"
+                    | "/api/v1/auth/refresh_token"
+                    | "/api/v1/health"
+                    | "/.well-known/jwks.json"
+                    | "/oauth/introspect"
+                    | "/oauth/revoke" => {
+                        log::debug!("skip the auth check of {}", req.path());
                         let res = ctx.call(&self.service, req).await?;
                         return Ok(add_cors_header(res, "*"));
                     }
@@ -63,86 +77,81 @@ where
                         // Do nothing and continue to the next middleware/service
                     }
                 }
-                // 2. After the preflight request, we can get the AUTHORIZATION header from the standard request.
-                if let Some(token) = req.headers().get(http::header::AUTHORIZATION) {
-                    let token = token.to_str().unwrap().replace("Bearer ", "");
-                    log::info!("token: {:?}", token);
-
-                    // 3. Verify the token by checking the Redis server.
-                    // Get a connection to the Redis server
-                    let mut conn = repository::redis::new()
-                        .ok()
-                        .unwrap()
-                        .clone()
-                        .get_multiplexed_async_connection()
-                        .await
-                        .ok()
-                        .unwrap();
-
-                    // 4. Call the next service in the chain if the token exists in the Redis server and can be **decoded** to the user ID correctly.
-                    if jwt::get_user_id_from_redis(&mut conn, jwt::TokenType::AccessToken, &token)
-                        .await
-                        .map_err(|e| {
-                            log::error!("Error getting user_id from redis: {}", e);
-                            AppError::Unauthorized
-                        })
-                        .ok()
-                        .is_some()
-                    {
-                        //if get user_id, Call the next service in the chain
-                        let res = ctx.call(&self.service, req).await?;
-                        Ok(add_cors_header(res, "*"))
-                    } else {
-                        log::error!("Invalid token");
-                        match req.path() {
-                            // logout and refresh token should carry a access token even if it's expired
-                            "/api/v1/auth/login"
-                            | "/api/v1/auth/logout"
-                            | "/api/v1/auth/refresh_token"
-                            | "/api/v1/health" => {
-                                let res = ctx.call(&self.service, req).await?;
-                                Ok(add_cors_header(res, "*"))
-                            }
-                            _ => {
-                                let res = req.into_response(
-                                    web::HttpResponse::Unauthorized().json(&Response::<()> {
-                                        status: "fail".to_string(),
-                                        message: "Invalid token".to_string(),
-                                        count: None,
-                                        data: None,
-                                    }),
-                                );
-                                Ok(add_cors_header(res, "*"))
+                // 2. After the preflight request, we can get the bearer token from the AUTHORIZATION header of the standard request.
+                if let Some(token) = bearer_token(req.headers()).map(str::to_owned) {
+                    let data = req.app_state::<Arc<AppState>>().cloned().ok_or_else(|| {
+                        AppError::InternalServerError("App state is not set".to_string())
+                    })?;
+
+                    // 3. Verify the token by checking the Redis server, and resolve the user who owns it.
+                    // 4. Call the next service in the chain if the token exists in the Redis server and can be **decoded** to an active user correctly.
+                    match CurrentUser::from_access_token(&data, &token).await {
+                        Ok(current_user) => {
+                            // attach the user to the request, handlers get it with the `CurrentUser` extractor
+                            req.extensions_mut().insert(current_user);
+                            let res = ctx.call(&self.service, req).await?;
+                            Ok(add_cors_header(res, "*"))
+                        }
+                        Err(e) => {
+                            log::error!("Invalid token: {}", e);
+                            match req.path() {
+                                // logout should carry an access token even if it's expired
+                                "/api/v1/auth/logout" => {
+                                    let res = ctx.call(&self.service, req).await?;
+                                    Ok(add_cors_header(res, "*"))
+                                }
+                                _ => {
+                                    let res = match e {
+                                        AppError::Unauthorized => req.into_response(
+                                            web::HttpResponse::Unauthorized().json(
+                                                &Response::<()> {
+                                                    status: "fail".to_string(),
+                                                    message: "Invalid token".to_string(),
+                                                    count: None,
+                                                    data: None,
+                                                },
+                                            ),
+                                        ),
+                                        // e.g. redis is down, the token couldn't be checked
+                                        e => req.render_error(e),
+                                    };
+                                    Ok(add_cors_header(res, "*"))
+                                }
                             }
                         }
                     }
                 // If no token is found, redirect to the login page
                 } else {
                     log::error!("No token found");
-                    // If no token is found, redirect to the login page
-                    match req.path() {
-                        "/api/v1/auth/login" | "/api/v1/auth/register" => {
-                            let res = ctx.call(&self.service, req).await?;
-                            Ok(add_cors_header(res, "*"))
-                        }
-                        _ => {
-                            let res = req.into_response(web::HttpResponse::Unauthorized().json(
-                                &Response::<()> {
-                                    status: "fail".to_string(),
-                                    message: "No token found".to_string(),
-                                    count: None,
-                                    data: None,
-                                },
-                            ));
-                            Ok(add_cors_header(res, "*"))
-                        }
-                    }
+                    // the public endpoints are already skipped, the others need a token
+                    let res = req.into_response(web::HttpResponse::Unauthorized().json(
+                        &Response::<()> {
+                            status: "fail".to_string(),
+                            message: "No token found".to_string(),
+                            count: None,
+                            data: None,
+                        },
+                    ));
+                    Ok(add_cors_header(res, "*"))
                 }
             }
         }
     }
 }
 
+/// get the token from a `Authorization: Bearer <token>` header.
+/// The scheme is case-insensitive, `None` is returned if the header is missing, not visible ASCII or uses another scheme.
+pub fn bearer_token(headers: &http::HeaderMap) -> Option<&str> {
+    let value = headers.get(http::header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = value.trim().split_once(' ')?;
+    let token = token.trim();
+    if scheme.eq_ignore_ascii_case("Bearer") && !token.is_empty() {
+        Some(token)
+    } else {
+        None
+    }
+}
+
 // add access_control_allow_origin header
 fn add_cors_header(mut res: WebResponse, origin: &'static str) -> WebResponse {
     res.headers_mut().insert(
@@ -151,3 +160,69 @@ fn add_cors_header(mut res: WebResponse, origin: &'static str) -> WebResponse {
     );
     res
 }
+
+#[cfg(test)]
+#[test]
+fn test_bearer_token() {
+    let headers_with = |value: &'static [u8]| {
+        let mut headers = http::HeaderMap::new();
+        headers.insert(
+            http::header::AUTHORIZATION,
+            http::header::HeaderValue::from_bytes(value).unwrap(),
+        );
+        headers
+    };
+
+    assert_eq!(
+        bearer_token(&headers_with(b"Bearer abc.def")),
+        Some("abc.def")
+    );
+    assert_eq!(
+        bearer_token(&headers_with(b"bearer  abc.def ")),
+        Some("abc.def")
+    );
+    assert_eq!(bearer_token(&headers_with(b"Basic dXNlcjpwd2Q=")), None);
+    assert_eq!(bearer_token(&headers_with(b"Bearer ")), None);
+    assert_eq!(bearer_token(&headers_with(b"abc.def")), None);
+    assert_eq!(bearer_token(&headers_with("Bearer 令牌".as_bytes())), None);
+    assert_eq!(bearer_token(&http::HeaderMap::new()), None);
+}
+
+#[cfg(test)]
+#[ntex::test]
+async fn test_public_endpoints() {
+    let app = web::test::init_service(
+        web::App::new()
+            .state(Arc::new(crate::test_state()))
+            .wrap(Auth)
+            .route(
+                "/api/v1/auth/password/forgot",
+                web::post().to(|| async { web::HttpResponse::Ok().finish() }),
+            )
+            .route(
+                "/api/v1/users/me",
+                web::get().to(|| async { web::HttpResponse::Ok().finish() }),
+            ),
+    )
+    .await;
+    let request = |req: web::test::TestRequest, token: Option<&str>| match token {
+        Some(token) => req
+            .header(http::header::AUTHORIZATION, format!("Bearer {}", token))
+            .to_request(),
+        None => req.to_request(),
+    };
+
+    // a stale token doesn't block the recovery of an account
+    for token in [None, Some("expired.or.invalid")] {
+        let req = request(
+            web::test::TestRequest::post().uri("/api/v1/auth/password/forgot"),
+            token,
+        );
+        let res = web::test::call_service(&app, req).await;
+        assert_eq!(res.status(), http::StatusCode::OK);
+
+        let req = request(web::test::TestRequest::get().uri("/api/v1/users/me"), token);
+        let res = web::test::call_service(&app, req).await;
+        assert_eq!(res.status(), http::StatusCode::UNAUTHORIZED);
+    }
+}
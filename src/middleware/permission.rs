@@ -0,0 +1,160 @@
+use ntex::http::Payload;
+use ntex::web::{self, ErrorRenderer, FromRequest, HttpRequest};
+
+use crate::errors::AppError;
+use crate::models::user::{self, Role};
+use crate::utils::jwt;
+use crate::AppState;
+
+/// The authenticated user who sends the request, it's resolved from the access token by the `Auth` middleware.
+/// Use it as a handler argument to get the caller and check its permissions,
+/// the request is rejected with 401 if the middleware couldn't resolve the access token to an active user.
+#[derive(Debug, Clone)]
+pub struct CurrentUser {
+    pub id: i32,
+    pub role: Role,
+    // id of the session the access token belongs to
+    pub session_id: String,
+    // id of the access token used by the request
+    pub token_id: String,
+}
+
+impl CurrentUser {
+    pub fn is_admin(&self) -> bool {
+        self.role == Role::Admin
+    }
+
+    /// only admins are allowed
+    pub fn require_admin(&self) -> Result<(), AppError> {
+        if self.is_admin() {
+            Ok(())
+        } else {
+            Err(AppError::Forbidden)
+        }
+    }
+
+    /// admins are allowed to access any user, regular users only their own record
+    pub fn require_self_or_admin(&self, user_id: i32) -> Result<(), AppError> {
+        if self.is_admin() || self.id == user_id {
+            Ok(())
+        } else {
+            Err(AppError::Forbidden)
+        }
+    }
+}
+
+impl CurrentUser {
+    /// resolve the user who owns the access token,
+    /// the token must be valid, recorded in the token store and belong to a user who isn't deleted.
+    pub async fn from_access_token(data: &AppState, token: &str) -> Result<Self, AppError> {
+        let principal =
+            jwt::decode_principal(&data.config, &data.keys, jwt::TokenType::AccessToken, token)
+                .map_err(|e| {
+                    log::error!("Failed to decode access token: {}", e);
+                    AppError::Unauthorized
+                })?;
+
+        // the access token must still be recorded in the token store for the user it was issued to,
+        // otherwise it has been revoked
+        if !jwt::is_active(data, &principal, jwt::TokenType::AccessToken).await? {
+            return Err(AppError::Unauthorized);
+        }
+
+        // the role in the token may be outdated, get the current one from postgresql database,
+        // a deleted user has no permissions
+        let pool = data.pool.clone();
+        let user_id = principal.user_id;
+        let users = web::block(move || {
+            // without the database the role can't be checked, every request is rejected until it's back
+            let mut conn = pool.get().map_err(|e| {
+                log::error!("Failed to get db connection: {}", e);
+                AppError::ServiceUnavailable
+            })?;
+            user::get_users_by_id(&mut conn, user_id).map_err(|e| {
+                log::error!("Failed to get user: {:?}", e);
+                AppError::InternalServerError(e.to_string())
+            })
+        })
+        .await
+        .map_err(|e| match e {
+            web::error::BlockingError::Error(e) => e,
+            web::error::BlockingError::Canceled => {
+                AppError::InternalServerError("User query canceled".to_string())
+            }
+        })?;
+        let user = users.first().ok_or(AppError::Unauthorized)?;
+
+        Ok(CurrentUser {
+            id: user.id,
+            role: user.role,
+            session_id: principal.session_id,
+            token_id: principal.token_id,
+        })
+    }
+}
+
+impl<Err: ErrorRenderer> FromRequest<Err> for CurrentUser {
+    type Error = AppError;
+
+    async fn from_request(req: &HttpRequest, _: &mut Payload) -> Result<Self, Self::Error> {
+        req.extensions()
+            .get::<CurrentUser>()
+            .cloned()
+            .ok_or(AppError::Unauthorized)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_permissions() {
+    let admin = CurrentUser {
+        id: 1,
+        role: Role::Admin,
+        session_id: "01HSJARKXDAH23Z8SF6ZY475TS".to_string(),
+        token_id: "01HSJARKXDAH23Z8SF6ZY475TV".to_string(),
+    };
+    assert!(admin.require_admin().is_ok());
+    assert!(admin.require_self_or_admin(2).is_ok());
+
+    let user = CurrentUser {
+        id: 2,
+        role: Role::User,
+        session_id: "01HSJARKXDAH23Z8SF6ZY475TT".to_string(),
+        token_id: "01HSJARKXDAH23Z8SF6ZY475TW".to_string(),
+    };
+    assert!(matches!(user.require_admin(), Err(AppError::Forbidden)));
+    assert!(user.require_self_or_admin(2).is_ok());
+    assert!(matches!(
+        user.require_self_or_admin(1),
+        Err(AppError::Forbidden)
+    ));
+}
+
+#[cfg(test)]
+#[ntex::test]
+async fn test_current_user_without_database() {
+    use crate::models::user::User;
+
+    let data = crate::test_state();
+    let user = User {
+        id: 2,
+        name: "elton".to_string(),
+        email: "elton@pwr.ink".to_string(),
+        avatar: None,
+        password: String::new(),
+        role: Role::User,
+        created_at: None,
+        modified_at: None,
+        deleted_at: None,
+        email_verified_at: None,
+    };
+    let token = jwt::issue_tokens(&data, &user, &jwt::ClientInfo::default())
+        .await
+        .unwrap();
+
+    // the token is valid, but the role of the user can't be checked
+    assert!(matches!(
+        CurrentUser::from_access_token(&data, &token.access_token).await,
+        Err(AppError::ServiceUnavailable)
+    ));
+}
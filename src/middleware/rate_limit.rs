@@ -0,0 +1,146 @@
+use ntex::http::{self, Method};
+use ntex::service::{Middleware, Service, ServiceCtx};
+use ntex::web::{Error, ErrorRenderer, WebRequest, WebResponse};
+
+use std::sync::Arc;
+
+use crate::config::RateLimitRule;
+use crate::errors::AppError;
+use crate::handlers::oauth;
+use crate::middleware::permission::CurrentUser;
+use crate::repository::rate_limit::{self, Quota};
+use crate::utils::jwt;
+use crate::AppState;
+
+// Limits the requests to the route groups in `RATE_LIMITS`, it's wrapped inside the `Auth` middleware
+// so authenticated users are counted by their id instead of their ip.
+// The other requests are counted by their oauth client, or by their ip, see `jwt::client_ip`.
+// Nothing is limited without redis, and the requests go through if redis fails.
+pub struct RateLimit;
+
+impl<S> Middleware<S> for RateLimit {
+    type Service = RateLimitMiddleware<S>;
+
+    fn create(&self, service: S) -> Self::Service {
+        RateLimitMiddleware { service }
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    service: S,
+}
+
+impl<S, Err> Service<WebRequest<Err>> for RateLimitMiddleware<S>
+where
+    S: Service<WebRequest<Err>, Response = WebResponse, Error = Error> + 'static,
+    Err: ErrorRenderer + 'static,
+{
+    type Response = WebResponse;
+    type Error = Error;
+
+    ntex::forward_ready!(service);
+
+    async fn call(
+        &self,
+        req: WebRequest<Err>,
+        ctx: ServiceCtx<'_, Self>,
+    ) -> Result<Self::Response, Self::Error> {
+        // preflight requests aren't counted
+        if req.head().method == Method::OPTIONS {
+            return ctx.call(&self.service, req).await;
+        }
+        let Some(data) = req.app_state::<Arc<AppState>>().cloned() else {
+            return ctx.call(&self.service, req).await;
+        };
+        let (Some(conn), Some(rule)) = (&data.redis, rule(&data.config.rate_limits, req.path()))
+        else {
+            return ctx.call(&self.service, req).await;
+        };
+        let Some(client) = client_key(&data, &req) else {
+            return ctx.call(&self.service, req).await;
+        };
+
+        let quota = match rate_limit::hit(conn, rule, &client).await {
+            Ok(quota) => quota,
+            Err(e) => {
+                log::error!("Failed to check the rate limit of {}: {}", client, e);
+                return ctx.call(&self.service, req).await;
+            }
+        };
+        let res = if quota.allowed {
+            ctx.call(&self.service, req).await?
+        } else {
+            log::warn!(
+                target: "security",
+                "{} exceeded the rate limit of {}",
+                client,
+                rule.path
+            );
+            req.render_error(AppError::TooManyRequests(quota.reset))
+        };
+        Ok(add_rate_limit_headers(res, rule, &quota))
+    }
+}
+
+// the rule of the longest path prefix matching the path
+fn rule<'a>(rules: &'a [RateLimitRule], path: &str) -> Option<&'a RateLimitRule> {
+    rules
+        .iter()
+        .filter(|rule| rule.matches(path))
+        .max_by_key(|rule| rule.path.trim_end_matches('/').len())
+}
+
+// who the request is counted for, the user, the oauth client or the ip
+fn client_key<Err>(data: &AppState, req: &WebRequest<Err>) -> Option<String> {
+    if let Some(user) = req.extensions().get::<CurrentUser>() {
+        return Some(format!("user:{}", user.id));
+    }
+    if let Some(client) = oauth::basic_client(&data.config.oauth_clients, req.headers()) {
+        return Some(format!("client:{}", client.id));
+    }
+    // the same ip as the failed logins, the forwarded headers are only read from trusted proxies
+    req.peer_addr().map(|peer| {
+        let ip = jwt::forwarded_client_ip(peer.ip(), req.headers(), &data.config.trusted_proxies);
+        format!("ip:{}", ip)
+    })
+}
+
+// the RateLimit headers of the IETF draft, so clients can slow down before they are rejected
+fn add_rate_limit_headers(
+    mut res: WebResponse,
+    rule: &RateLimitRule,
+    quota: &Quota,
+) -> WebResponse {
+    let headers = res.headers_mut();
+    for (name, value) in [
+        ("ratelimit-limit", quota.limit.to_string()),
+        ("ratelimit-remaining", quota.remaining.to_string()),
+        ("ratelimit-reset", quota.reset.to_string()),
+        (
+            "ratelimit-policy",
+            format!("{};w={}", rule.limit, rule.window),
+        ),
+    ] {
+        if let Ok(value) = http::header::HeaderValue::from_str(&value) {
+            headers.insert(http::header::HeaderName::from_static(name), value);
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+#[test]
+fn test_rule() {
+    let rules: Vec<RateLimitRule> = ["/api/v1/users=120/60", "/api/v1/users/search=30/60"]
+        .iter()
+        .map(|rule| rule.parse().unwrap())
+        .collect();
+
+    assert_eq!(rule(&rules, "/api/v1/users").unwrap().limit, 120);
+    assert_eq!(
+        rule(&rules, "/api/v1/users/me/sessions").unwrap().limit,
+        120
+    );
+    assert_eq!(rule(&rules, "/api/v1/users/search").unwrap().limit, 30);
+    assert!(rule(&rules, "/api/v1/auth/login").is_none());
+}
@@ -0,0 +1,94 @@
+use ::r2d2::PooledConnection;
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use diesel::r2d2::ConnectionManager;
+use serde::{Deserialize, Serialize};
+
+use crate::models::user;
+
+// verify the email with the token of the link
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct VerifyEmail {
+    pub token: String,
+}
+
+// ask for a new verification link
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ResendVerification {
+    pub email: String,
+}
+
+// save the hash of a new verification token sent to the email of a user,
+// the previous tokens of the user are replaced
+pub fn create_verification_token(
+    conn: &mut PooledConnection<ConnectionManager<PgConnection>>,
+    user_id: i32,
+    email: &str,
+    token_hash: &str,
+    expires_at: DateTime<Utc>,
+) -> diesel::QueryResult<()> {
+    use crate::models::schema::email_verification_tokens;
+
+    conn.transaction(|conn| {
+        diesel::delete(
+            email_verification_tokens::table.filter(email_verification_tokens::user_id.eq(user_id)),
+        )
+        .execute(conn)?;
+        diesel::insert_into(email_verification_tokens::table)
+            .values((
+                email_verification_tokens::token_hash.eq(token_hash),
+                email_verification_tokens::user_id.eq(user_id),
+                email_verification_tokens::email.eq(email),
+                email_verification_tokens::expires_at.eq(expires_at),
+                email_verification_tokens::created_at.eq(Utc::now()),
+            ))
+            .execute(conn)?;
+        Ok(())
+    })
+}
+
+// when the last verification link was sent to a user, `None` if there is none pending
+pub fn last_sent_at(
+    conn: &mut PooledConnection<ConnectionManager<PgConnection>>,
+    user_id: i32,
+) -> diesel::QueryResult<Option<DateTime<Utc>>> {
+    use crate::models::schema::email_verification_tokens;
+
+    email_verification_tokens::table
+        .filter(email_verification_tokens::user_id.eq(user_id))
+        .select(diesel::dsl::max(email_verification_tokens::created_at))
+        .first(conn)
+}
+
+// verify the email of the user of a token, the token is spent even if it has expired.
+// `None` if the token is unknown, already used or expired, or the user has changed their email since
+pub fn verify_email(
+    conn: &mut PooledConnection<ConnectionManager<PgConnection>>,
+    token_hash: &str,
+) -> diesel::QueryResult<Option<user::User>> {
+    use crate::models::schema::email_verification_tokens;
+
+    conn.transaction(|conn| {
+        let token: Option<(i32, String, DateTime<Utc>)> = diesel::delete(
+            email_verification_tokens::table
+                .filter(email_verification_tokens::token_hash.eq(token_hash)),
+        )
+        .returning((
+            email_verification_tokens::user_id,
+            email_verification_tokens::email,
+            email_verification_tokens::expires_at,
+        ))
+        .get_result(conn)
+        .optional()?;
+        let Some((user_id, email, _)) = token.filter(|(_, _, expires_at)| *expires_at > Utc::now())
+        else {
+            return Ok(None);
+        };
+
+        diesel::delete(
+            email_verification_tokens::table.filter(email_verification_tokens::user_id.eq(user_id)),
+        )
+        .execute(conn)?;
+        user::verify_email(conn, user_id, &email)
+    })
+}
@@ -0,0 +1,298 @@
+use ::r2d2::PooledConnection;
+use chrono::Utc;
+use diesel::prelude::*;
+use diesel::r2d2::ConnectionManager;
+use serde::Serialize;
+
+use crate::utils::{jwt::MfaChallenge, totp};
+
+// the wrong codes a challenge token can be exchanged with before it's rejected
+const MAX_CHALLENGE_ATTEMPTS: i32 = 5;
+
+// The TOTP secret of a user, it's pending until the user confirms the enrollment with a code.
+// It isn't serializable on purpose, the secret is only shown once when it's enrolled.
+#[derive(Queryable, Selectable, Debug, Clone)]
+#[diesel(table_name = crate::models::schema::user_mfa)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct UserMfa {
+    pub secret: String,
+    pub enabled_at: Option<chrono::DateTime<Utc>>,
+}
+
+impl UserMfa {
+    pub fn is_enabled(&self) -> bool {
+        self.enabled_at.is_some()
+    }
+}
+
+// the mfa status of a user returned by the api
+#[derive(Serialize, Debug, Clone)]
+pub struct MfaStatus {
+    pub enabled: bool,
+    pub enabled_at: Option<chrono::DateTime<Utc>>,
+    pub recovery_codes_left: i64,
+}
+
+// a new TOTP secret, shown once to be added to an authenticator app
+#[derive(Serialize, Debug, Clone)]
+pub struct MfaEnrollment {
+    pub secret: String,
+    pub otpauth_uri: String,
+}
+
+// a TOTP code, or a recovery code
+#[derive(serde::Deserialize, Serialize, Debug, Clone)]
+pub struct MfaCode {
+    pub code: String,
+}
+
+// get the mfa of a user, pending or enabled
+pub fn get_mfa(
+    conn: &mut PooledConnection<ConnectionManager<PgConnection>>,
+    user_id: i32,
+) -> diesel::QueryResult<Option<UserMfa>> {
+    use crate::models::schema::user_mfa;
+
+    user_mfa::table
+        .find(user_id)
+        .select(UserMfa::as_select())
+        .first(conn)
+        .optional()
+}
+
+// whether the second factor is required to log in
+pub fn is_enabled(
+    conn: &mut PooledConnection<ConnectionManager<PgConnection>>,
+    user_id: i32,
+) -> diesel::QueryResult<bool> {
+    Ok(get_mfa(conn, user_id)?.is_some_and(|mfa| mfa.is_enabled()))
+}
+
+// the mfa status of a user
+pub fn get_status(
+    conn: &mut PooledConnection<ConnectionManager<PgConnection>>,
+    user_id: i32,
+) -> diesel::QueryResult<MfaStatus> {
+    use crate::models::schema::mfa_recovery_codes;
+
+    let mfa = get_mfa(conn, user_id)?.filter(UserMfa::is_enabled);
+    let recovery_codes_left = mfa_recovery_codes::table
+        .filter(
+            mfa_recovery_codes::user_id
+                .eq(user_id)
+                .and(mfa_recovery_codes::used_at.is_null()),
+        )
+        .count()
+        .get_result(conn)?;
+    Ok(MfaStatus {
+        enabled: mfa.is_some(),
+        enabled_at: mfa.and_then(|mfa| mfa.enabled_at),
+        recovery_codes_left,
+    })
+}
+
+// save a new pending secret for a user, replacing a pending one.
+// `None` if the mfa is already enabled, it must be disabled first.
+pub fn start_enrollment(
+    conn: &mut PooledConnection<ConnectionManager<PgConnection>>,
+    user_id: i32,
+    new_secret: &str,
+) -> diesel::QueryResult<Option<UserMfa>> {
+    use crate::models::schema::user_mfa;
+
+    conn.transaction(|conn| {
+        // an enabled secret is kept
+        if is_enabled(conn, user_id)? {
+            return Ok(None);
+        }
+        diesel::insert_into(user_mfa::table)
+            .values((
+                user_mfa::user_id.eq(user_id),
+                user_mfa::secret.eq(new_secret),
+                user_mfa::created_at.eq(Utc::now()),
+            ))
+            .on_conflict(user_mfa::user_id)
+            .do_update()
+            .set((
+                user_mfa::secret.eq(new_secret),
+                user_mfa::created_at.eq(Utc::now()),
+            ))
+            .returning(UserMfa::as_returning())
+            .get_result(conn)
+            .map(Some)
+    })
+}
+
+// enable the pending mfa of a user with the step of the confirming code, and replace its recovery codes
+pub fn enable(
+    conn: &mut PooledConnection<ConnectionManager<PgConnection>>,
+    user_id: i32,
+    step: u64,
+    recovery_codes: &[String],
+) -> diesel::QueryResult<()> {
+    use crate::models::schema::{mfa_recovery_codes, user_mfa};
+
+    conn.transaction(|conn| {
+        diesel::update(user_mfa::table.find(user_id))
+            .set((
+                user_mfa::enabled_at.eq(Some(Utc::now())),
+                user_mfa::last_used_step.eq(Some(step as i64)),
+            ))
+            .execute(conn)?;
+        diesel::delete(mfa_recovery_codes::table.filter(mfa_recovery_codes::user_id.eq(user_id)))
+            .execute(conn)?;
+        let codes: Vec<_> = recovery_codes
+            .iter()
+            .map(|code| {
+                (
+                    mfa_recovery_codes::user_id.eq(user_id),
+                    mfa_recovery_codes::code_hash.eq(totp::hash_recovery_code(code)),
+                )
+            })
+            .collect();
+        diesel::insert_into(mfa_recovery_codes::table)
+            .values(&codes)
+            .execute(conn)?;
+        Ok(())
+    })
+}
+
+// record the use of a TOTP code, `false` if a code of the same or a later step was already used
+fn use_step(
+    conn: &mut PooledConnection<ConnectionManager<PgConnection>>,
+    user_id: i32,
+    step: u64,
+) -> diesel::QueryResult<bool> {
+    use crate::models::schema::user_mfa;
+
+    let step = step as i64;
+    let updated = diesel::update(
+        user_mfa::table.filter(
+            user_mfa::user_id.eq(user_id).and(
+                user_mfa::last_used_step
+                    .is_null()
+                    .or(user_mfa::last_used_step.lt(step)),
+            ),
+        ),
+    )
+    .set(user_mfa::last_used_step.eq(Some(step)))
+    .execute(conn)?;
+    Ok(updated == 1)
+}
+
+// spend a recovery code of a user, `false` if it's unknown or already used
+fn use_recovery_code(
+    conn: &mut PooledConnection<ConnectionManager<PgConnection>>,
+    user_id: i32,
+    code: &str,
+) -> diesel::QueryResult<bool> {
+    use crate::models::schema::mfa_recovery_codes;
+
+    // the codes are random, two of them with the same hash are only a theoretical case
+    let unused = mfa_recovery_codes::table
+        .filter(
+            mfa_recovery_codes::user_id
+                .eq(user_id)
+                .and(mfa_recovery_codes::code_hash.eq(totp::hash_recovery_code(code)))
+                .and(mfa_recovery_codes::used_at.is_null()),
+        )
+        .select(mfa_recovery_codes::id)
+        .first::<i32>(conn)
+        .optional()?;
+    let Some(code_id) = unused else {
+        return Ok(false);
+    };
+    let updated = diesel::update(
+        mfa_recovery_codes::table.filter(
+            mfa_recovery_codes::id
+                .eq(code_id)
+                .and(mfa_recovery_codes::used_at.is_null()),
+        ),
+    )
+    .set(mfa_recovery_codes::used_at.eq(Some(Utc::now())))
+    .execute(conn)?;
+    Ok(updated == 1)
+}
+
+// verify a TOTP code or a recovery code of the enabled mfa of a user, each code is only accepted once
+pub fn verify_code(
+    conn: &mut PooledConnection<ConnectionManager<PgConnection>>,
+    user_id: i32,
+    code: &str,
+) -> diesel::QueryResult<bool> {
+    let Some(mfa) = get_mfa(conn, user_id)?.filter(UserMfa::is_enabled) else {
+        return Ok(false);
+    };
+    match totp::verify(&mfa.secret, code, Utc::now().timestamp() as u64) {
+        Some(step) => use_step(conn, user_id, step),
+        None => use_recovery_code(conn, user_id, code),
+    }
+}
+
+// exchange an mfa challenge with a code of its user, `false` if the code is wrong, or the challenge
+// was already exchanged or got too many wrong codes. The challenge is locked meanwhile,
+// the codes sent with it at the same time are checked one after the other
+pub fn verify_challenge(
+    conn: &mut PooledConnection<ConnectionManager<PgConnection>>,
+    challenge: &MfaChallenge,
+    code: &str,
+) -> diesel::QueryResult<bool> {
+    use crate::models::schema::mfa_challenges;
+
+    conn.transaction(|conn| {
+        // the expired challenges of the user are cleaned up on the way
+        diesel::delete(
+            mfa_challenges::table.filter(
+                mfa_challenges::user_id
+                    .eq(challenge.user_id)
+                    .and(mfa_challenges::expires_at.lt(Utc::now())),
+            ),
+        )
+        .execute(conn)?;
+        diesel::insert_into(mfa_challenges::table)
+            .values((
+                mfa_challenges::jti.eq(&challenge.jti),
+                mfa_challenges::user_id.eq(challenge.user_id),
+                mfa_challenges::expires_at.eq(challenge.expires_at),
+            ))
+            .on_conflict_do_nothing()
+            .execute(conn)?;
+        let (failed_attempts, used_at): (i32, Option<chrono::DateTime<Utc>>) =
+            mfa_challenges::table
+                .find(&challenge.jti)
+                .select((mfa_challenges::failed_attempts, mfa_challenges::used_at))
+                .for_update()
+                .first(conn)?;
+        if used_at.is_some() || failed_attempts >= MAX_CHALLENGE_ATTEMPTS {
+            return Ok(false);
+        }
+
+        let verified = verify_code(conn, challenge.user_id, code)?;
+        let challenge = mfa_challenges::table.find(&challenge.jti);
+        if verified {
+            diesel::update(challenge)
+                .set(mfa_challenges::used_at.eq(Some(Utc::now())))
+                .execute(conn)?;
+        } else {
+            diesel::update(challenge)
+                .set(mfa_challenges::failed_attempts.eq(mfa_challenges::failed_attempts + 1))
+                .execute(conn)?;
+        }
+        Ok(verified)
+    })
+}
+
+// remove the mfa of a user and its recovery codes
+pub fn disable(
+    conn: &mut PooledConnection<ConnectionManager<PgConnection>>,
+    user_id: i32,
+) -> diesel::QueryResult<()> {
+    use crate::models::schema::{mfa_recovery_codes, user_mfa};
+
+    conn.transaction(|conn| {
+        diesel::delete(mfa_recovery_codes::table.filter(mfa_recovery_codes::user_id.eq(user_id)))
+            .execute(conn)?;
+        diesel::delete(user_mfa::table.find(user_id)).execute(conn)?;
+        Ok(())
+    })
+}
@@ -1,2 +1,5 @@
+pub mod email_verification;
+pub mod mfa;
+pub mod password_reset;
 pub mod schema;
 pub mod user;
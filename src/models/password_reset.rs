@@ -0,0 +1,95 @@
+use ::r2d2::PooledConnection;
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use diesel::r2d2::ConnectionManager;
+use serde::{Deserialize, Serialize};
+
+use crate::models::user;
+
+// ask for a password reset link
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ForgotPassword {
+    pub email: String,
+}
+
+// reset the password with the token of the link
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ResetPassword {
+    pub token: String,
+    pub new_password: String,
+}
+
+// save the hash of a new reset token of a user, the previous tokens of the user are replaced
+pub fn create_reset_token(
+    conn: &mut PooledConnection<ConnectionManager<PgConnection>>,
+    user_id: i32,
+    token_hash: &str,
+    expires_at: DateTime<Utc>,
+) -> diesel::QueryResult<()> {
+    use crate::models::schema::password_reset_tokens;
+
+    conn.transaction(|conn| {
+        diesel::delete(
+            password_reset_tokens::table.filter(password_reset_tokens::user_id.eq(user_id)),
+        )
+        .execute(conn)?;
+        diesel::insert_into(password_reset_tokens::table)
+            .values((
+                password_reset_tokens::token_hash.eq(token_hash),
+                password_reset_tokens::user_id.eq(user_id),
+                password_reset_tokens::expires_at.eq(expires_at),
+                password_reset_tokens::created_at.eq(Utc::now()),
+            ))
+            .execute(conn)?;
+        Ok(())
+    })
+}
+
+// when the last reset link was sent to a user, `None` if there is none pending
+pub fn last_sent_at(
+    conn: &mut PooledConnection<ConnectionManager<PgConnection>>,
+    user_id: i32,
+) -> diesel::QueryResult<Option<DateTime<Utc>>> {
+    use crate::models::schema::password_reset_tokens;
+
+    password_reset_tokens::table
+        .filter(password_reset_tokens::user_id.eq(user_id))
+        .select(diesel::dsl::max(password_reset_tokens::created_at))
+        .first(conn)
+}
+
+// set the new password of the user of a reset token, the token is spent even if it has expired.
+// `None` if the token is unknown, already used or expired, or its user has been deleted.
+pub fn reset_password(
+    conn: &mut PooledConnection<ConnectionManager<PgConnection>>,
+    token_hash: &str,
+    new_pwd: &str,
+) -> diesel::QueryResult<Option<user::User>> {
+    use crate::models::schema::password_reset_tokens;
+
+    conn.transaction(|conn| {
+        let token: Option<(i32, DateTime<Utc>)> = diesel::delete(
+            password_reset_tokens::table.filter(password_reset_tokens::token_hash.eq(token_hash)),
+        )
+        .returning((
+            password_reset_tokens::user_id,
+            password_reset_tokens::expires_at,
+        ))
+        .get_result(conn)
+        .optional()?;
+        let Some((user_id, _)) = token.filter(|(_, expires_at)| *expires_at > Utc::now()) else {
+            return Ok(None);
+        };
+
+        // the other links of the user are useless now
+        diesel::delete(
+            password_reset_tokens::table.filter(password_reset_tokens::user_id.eq(user_id)),
+        )
+        .execute(conn)?;
+        match user::set_password(conn, user_id, new_pwd) {
+            Ok(user) => Ok(Some(user)),
+            Err(diesel::result::Error::NotFound) => Ok(None),
+            Err(e) => Err(e),
+        }
+    })
+}
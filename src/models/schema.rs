@@ -15,5 +15,106 @@ diesel::table! {
         created_at -> Nullable<Timestamptz>,
         modified_at -> Nullable<Timestamptz>,
         deleted_at -> Nullable<Timestamptz>,
+        email_verified_at -> Nullable<Timestamptz>,
     }
 }
+
+diesel::table! {
+    email_verification_tokens (token_hash) {
+        #[max_length = 64]
+        token_hash -> Varchar,
+        user_id -> Int4,
+        email -> Varchar,
+        expires_at -> Timestamptz,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    mfa_challenges (jti) {
+        #[max_length = 26]
+        jti -> Varchar,
+        user_id -> Int4,
+        failed_attempts -> Int4,
+        used_at -> Nullable<Timestamptz>,
+        expires_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    mfa_recovery_codes (id) {
+        id -> Int4,
+        user_id -> Int4,
+        #[max_length = 64]
+        code_hash -> Varchar,
+        used_at -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    password_reset_tokens (token_hash) {
+        #[max_length = 64]
+        token_hash -> Varchar,
+        user_id -> Int4,
+        expires_at -> Timestamptz,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    rotated_tokens (token_id) {
+        #[max_length = 26]
+        token_id -> Varchar,
+        #[max_length = 26]
+        session_id -> Varchar,
+    }
+}
+
+diesel::table! {
+    sessions (id) {
+        #[max_length = 26]
+        id -> Varchar,
+        user_id -> Int4,
+        #[max_length = 26]
+        access_token_id -> Varchar,
+        access_expires_at -> Timestamptz,
+        #[max_length = 26]
+        refresh_token_id -> Varchar,
+        expires_at -> Timestamptz,
+        created_at -> Timestamptz,
+        last_used_at -> Timestamptz,
+        #[max_length = 64]
+        ip -> Nullable<Varchar>,
+        user_agent -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    user_mfa (user_id) {
+        user_id -> Int4,
+        #[max_length = 64]
+        secret -> Varchar,
+        enabled_at -> Nullable<Timestamptz>,
+        last_used_step -> Nullable<Int8>,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::joinable!(email_verification_tokens -> users (user_id));
+diesel::joinable!(mfa_challenges -> users (user_id));
+diesel::joinable!(mfa_recovery_codes -> users (user_id));
+diesel::joinable!(password_reset_tokens -> users (user_id));
+diesel::joinable!(rotated_tokens -> sessions (session_id));
+diesel::joinable!(sessions -> users (user_id));
+diesel::joinable!(user_mfa -> users (user_id));
+
+diesel::allow_tables_to_appear_in_same_query!(
+    email_verification_tokens,
+    mfa_challenges,
+    mfa_recovery_codes,
+    password_reset_tokens,
+    rotated_tokens,
+    sessions,
+    user_mfa,
+    users,
+);
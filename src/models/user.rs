@@ -45,14 +45,13 @@ impl FromSql<VarChar, Pg> for Role {
 }
 // Queryable will generate all of the code needed to load a Post struct from a SQL query. Later, you can use User::as_select() to generate a select clause for your model type based on the table defined via the #[diesel(table_name = "your_table_name")] attribute.
 // Selectable will generate code to construct a matching select clause based on your model type based on the table defined via the #[diesel(table_name = "your_table_name")] attribute.
-#[derive(Queryable, Selectable, Serialize, Deserialize, Debug, Clone)]
+// It isn't serializable on purpose, the password hash must never leave the server, return `UserView` or `AdminUserView` instead.
+#[derive(Queryable, Selectable, Debug, Clone)]
 #[diesel(table_name = crate::models::schema::users)]
 // checks to verify that all field types in your struct are compatible with the backend you are using.
 #[diesel(check_for_backend(diesel::pg::Pg))]
 // the order of the fields in the struct must match the order of the columns in the table and schema.
-
 // [derive(Selectable)] + #[diesel(check_for_backend(YourBackendType))] to check for mismatching fields at compile time. This drastically improves the quality of the generated error messages by pointing to concrete type mismatches at field level.You need to specify the concrete database backend this specific struct is indented to be used with, as otherwise rustc cannot correctly identify the required deserialization implementation.
-
 pub struct User {
     pub id: i32,
     pub name: String,
@@ -63,6 +62,77 @@ pub struct User {
     pub created_at: Option<chrono::DateTime<Utc>>,
     pub modified_at: Option<chrono::DateTime<Utc>>,
     pub deleted_at: Option<chrono::DateTime<Utc>>,
+    // `None` until the user opens the link of the verification email
+    pub email_verified_at: Option<chrono::DateTime<Utc>>,
+}
+
+impl User {
+    pub fn is_email_verified(&self) -> bool {
+        self.email_verified_at.is_some()
+    }
+}
+
+// the public view of a user returned by the api
+#[derive(Serialize, Debug, Clone)]
+pub struct UserView {
+    pub id: i32,
+    pub name: String,
+    pub email: String,
+    pub avatar: Option<String>,
+    pub role: Role,
+    pub created_at: Option<chrono::DateTime<Utc>>,
+    pub modified_at: Option<chrono::DateTime<Utc>>,
+    pub email_verified_at: Option<chrono::DateTime<Utc>>,
+}
+
+impl From<User> for UserView {
+    fn from(user: User) -> Self {
+        Self {
+            id: user.id,
+            name: user.name,
+            email: user.email,
+            avatar: user.avatar,
+            role: user.role,
+            created_at: user.created_at,
+            modified_at: user.modified_at,
+            email_verified_at: user.email_verified_at,
+        }
+    }
+}
+
+// the view of a user returned to admins, with the fields used to manage users
+#[derive(Serialize, Debug, Clone)]
+pub struct AdminUserView {
+    #[serde(flatten)]
+    pub user: UserView,
+    pub deleted_at: Option<chrono::DateTime<Utc>>,
+}
+
+impl From<User> for AdminUserView {
+    fn from(user: User) -> Self {
+        let deleted_at = user.deleted_at;
+        Self {
+            user: user.into(),
+            deleted_at,
+        }
+    }
+}
+
+// the view of a user depending on the role of the caller, admins get `AdminUserView`
+#[derive(Serialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum ScopedUserView {
+    User(UserView),
+    Admin(AdminUserView),
+}
+
+impl ScopedUserView {
+    pub fn new(viewer: Role, user: User) -> Self {
+        match viewer {
+            Role::Admin => ScopedUserView::Admin(user.into()),
+            Role::User => ScopedUserView::User(user.into()),
+        }
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone, Insertable, AsChangeset)]
@@ -86,16 +156,121 @@ pub struct UserLogin {
     pub password: String,
 }
 
+// change password of the current user
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ChangePassword {
+    pub old_password: String,
+    pub new_password: String,
+}
+
+// set password of a user by admin
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct SetPassword {
+    pub id: i32,
+    pub new_password: String,
+}
+
+// columns users can be sorted by
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SortColumn {
+    Id,
+    Name,
+    Email,
+    Role,
+    CreatedAt,
+    ModifiedAt,
+}
+
+// sort direction
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    // the previous api took the SQL keywords
+    #[default]
+    #[serde(alias = "ASC")]
+    Asc,
+    #[serde(alias = "DESC")]
+    Desc,
+}
+
+// sort by a column in a direction, e.g. {"sort_by": "created_at", "order_by": "desc"}
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SortSpec {
+    pub sort_by: SortColumn,
+    #[serde(default)]
+    pub order_by: SortOrder,
+}
+
+type UserOrder = Box<
+    dyn BoxableExpression<
+            crate::models::schema::users::table,
+            Pg,
+            SqlType = diesel::expression::expression_types::NotSelectable,
+        > + Send,
+>;
+
+impl SortSpec {
+    // the ORDER BY expression of the spec
+    fn expression(&self) -> UserOrder {
+        use crate::models::schema::users::dsl::*;
+
+        macro_rules! ordered {
+            ($column:expr) => {
+                match self.order_by {
+                    SortOrder::Asc => Box::new($column.asc()),
+                    SortOrder::Desc => Box::new($column.desc()),
+                }
+            };
+        }
+
+        match self.sort_by {
+            SortColumn::Id => ordered!(id),
+            SortColumn::Name => ordered!(name),
+            SortColumn::Email => ordered!(email),
+            SortColumn::Role => ordered!(role),
+            SortColumn::CreatedAt => ordered!(created_at),
+            SortColumn::ModifiedAt => ordered!(modified_at),
+        }
+    }
+}
+
 // search query
-#[derive(Queryable, Deserialize, Serialize, Debug, Clone)]
+// unknown sort columns or directions are rejected when the query is deserialized
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct SearchQuery {
     pub search_term: String,
-    pub sort_by: String,
-    pub order_by: String,
+    // sort by multiple columns in order, the newest users come first by default
+    #[serde(default)]
+    pub sort: Vec<SortSpec>,
+    // the single sort of the previous api, e.g. {"sort_by": "name", "order_by": "desc"},
+    // it's still accepted when `sort` isn't given
+    pub sort_by: Option<SortColumn>,
+    pub order_by: Option<SortOrder>,
     pub page: i64,
     pub page_size: i64,
 }
 
+impl SearchQuery {
+    /// the columns to sort by, the `sort_by` and `order_by` of the previous api are a single spec
+    pub fn sort_specs(&self) -> Vec<SortSpec> {
+        if !self.sort.is_empty() {
+            return self.sort.clone();
+        }
+        match (self.sort_by, self.order_by) {
+            (Some(sort_by), order_by) => vec![SortSpec {
+                sort_by,
+                order_by: order_by.unwrap_or_default(),
+            }],
+            (None, Some(order_by)) => vec![SortSpec {
+                sort_by: SortColumn::CreatedAt,
+                order_by,
+            }],
+            (None, None) => Vec::new(),
+        }
+    }
+}
+
 // get a user by email
 pub fn get_user_by_email(
     conn: &mut PooledConnection<ConnectionManager<PgConnection>>,
@@ -110,23 +285,49 @@ pub fn get_user_by_email(
         .optional()
 }
 
-// create a new user
-pub fn create_user(
-    conn: &mut PooledConnection<ConnectionManager<PgConnection>>,
-    user: NewUser,
-) -> diesel::QueryResult<User> {
-    use crate::models::schema::users::dsl::*;
-
+// hash a password with Argon2 and a random salt
+fn hash_password(pwd: &str) -> diesel::QueryResult<String> {
     let salt = SaltString::generate(&mut OsRng);
-    let hashed_password = Argon2::default()
-        .hash_password(user.password.unwrap().as_bytes(), &salt)
+    Argon2::default()
+        .hash_password(pwd.as_bytes(), &salt)
         .map_err(|e| {
             log::error!("Error while hashing password: {:?}", e);
             diesel::result::Error::DeserializationError(
                 format!("Error while hashing password: {}", e).into(),
             )
         })
-        .map(|hash| hash.to_string())?;
+        .map(|hash| hash.to_string())
+}
+
+// verify a password against the Argon2 hash of the user
+fn verify_password(user: &User, pwd: &str) -> diesel::QueryResult<bool> {
+    let hash = PasswordHash::new(&user.password).map_err(|e| {
+        log::error!("Error while verifying password: {:?}", e);
+        diesel::result::Error::DeserializationError(
+            format!("Error while verifying password: {}", e).into(),
+        )
+    })?;
+
+    Ok(argon2::Argon2::default()
+        .verify_password(pwd.as_bytes(), &hash)
+        .map_err(|e| match e {
+            // an ordinary failed login, not an error of the server
+            argon2::password_hash::Error::Password => {
+                log::warn!(target: "security", "wrong password for user {}", user.id)
+            }
+            e => log::error!("Error while verifying password: {:?}", e),
+        })
+        .is_ok())
+}
+
+// create a new user
+pub fn create_user(
+    conn: &mut PooledConnection<ConnectionManager<PgConnection>>,
+    user: NewUser,
+) -> diesel::QueryResult<User> {
+    use crate::models::schema::users::dsl::*;
+
+    let hashed_password = hash_password(&user.password.unwrap())?;
 
     let user = NewUser {
         password: Some(hashed_password),
@@ -152,29 +353,49 @@ pub fn verify_user(
         .optional()?;
 
     match user {
-        Some(user) => {
-            match PasswordHash::new(&user.password).map_err(|e| {
-                log::error!("Error while verifying password: {:?}", e);
-                diesel::result::Error::DeserializationError(
-                    format!("Error while verifying password: {}", e).into(),
-                )
-            }) {
-                Ok(hash) => argon2::Argon2::default()
-                    .verify_password(pwd.as_bytes(), &hash)
-                    .map_err(|e| {
-                        log::error!("Error while verifying password: {:?}", e);
-                        diesel::result::Error::DeserializationError(
-                            format!("Error while verifying password: {}", e).into(),
-                        )
-                    })
-                    .map_or(Ok(None), |_| Ok(Some(user))),
-                Err(e) => Err(e),
-            }
-        }
+        Some(user) => verify_password(&user, pwd).map(|verified| verified.then_some(user)),
         None => Ok(None),
     }
 }
 
+// change the password of a user after verifying the old one, `None` if the old password is wrong
+pub fn change_password(
+    conn: &mut PooledConnection<ConnectionManager<PgConnection>>,
+    user_id: i32,
+    old_pwd: &str,
+    new_pwd: &str,
+) -> diesel::QueryResult<Option<User>> {
+    use crate::models::schema::users::dsl::*;
+
+    let user = users
+        .filter(id.eq(user_id).and(deleted_at.is_null()))
+        .select(User::as_select())
+        .first::<User>(conn)?;
+
+    if !verify_password(&user, old_pwd)? {
+        return Ok(None);
+    }
+
+    set_password(conn, user_id, new_pwd).map(Some)
+}
+
+// set a new password for a user, the password is hashed the same way as `create_user`
+pub fn set_password(
+    conn: &mut PooledConnection<ConnectionManager<PgConnection>>,
+    user_id: i32,
+    new_pwd: &str,
+) -> diesel::QueryResult<User> {
+    use crate::models::schema::users::dsl::*;
+
+    let hashed_password = hash_password(new_pwd)?;
+    diesel::update(users.filter(id.eq(user_id).and(deleted_at.is_null())))
+        .set((
+            password.eq(hashed_password),
+            modified_at.eq(Some(chrono::Utc::now())),
+        ))
+        .get_result(conn)
+}
+
 // get a user by id
 pub fn get_users_by_id(
     conn: &mut PooledConnection<ConnectionManager<PgConnection>>,
@@ -211,29 +432,30 @@ pub fn get_users_by_name(
 pub fn search_users(
     conn: &mut PooledConnection<ConnectionManager<PgConnection>>,
     search_term: &str,
-    sort_by: &str,
-    order_by: &str,
+    sort: &[SortSpec],
     page: i64,
     page_size: i64,
 ) -> diesel::QueryResult<(Vec<User>, i64)> {
     use crate::models::schema::users::dsl::*;
 
     let offset = (page - 1) * page_size;
+    let pattern = format!("%{}%", &search_term);
 
-    let user_list = users
+    let mut query = users
         .filter(
-            name.ilike(&format!("%{}%", &search_term))
-                .or(email.ilike(&format!("%{}%", &search_term)))
+            name.ilike(&pattern)
+                .or(email.ilike(&pattern))
                 .and(deleted_at.is_null()),
         )
         .select(User::as_select())
-        .order_by(diesel::dsl::sql::<diesel::sql_types::Text>(&format!(
-            "{} {}",
-            sort_by, order_by
-        )))
-        .offset(offset)
-        .limit(page_size)
-        .load(conn)?;
+        .into_boxed();
+    if sort.is_empty() {
+        query = query.order_by(created_at.desc());
+    }
+    for spec in sort {
+        query = query.then_order_by(spec.expression());
+    }
+    let user_list = query.offset(offset).limit(page_size).load(conn)?;
 
     let total_count = users
         .filter(
@@ -247,18 +469,63 @@ pub fn search_users(
 }
 
 // update a user by id
+// the password can't be updated here, otherwise it would be saved in plaintext, use `set_password` instead.
+// A new email has to be verified again, the updated user is returned with whether its email changed
 pub fn update_user_by_id(
     conn: &mut PooledConnection<ConnectionManager<PgConnection>>,
     user_id: i32,
     mut user: NewUser,
-) -> diesel::QueryResult<User> {
+) -> diesel::QueryResult<(User, bool)> {
     use crate::models::schema::users::dsl::*;
 
+    if user.password.is_some() {
+        return Err(diesel::result::Error::QueryBuilderError(
+            "Password can't be updated with the user, use set_password instead".into(),
+        ));
+    }
+
     user.modified_at = Some(chrono::Utc::now());
 
-    diesel::update(users.find(user_id))
-        .set(user)
-        .get_result(conn)
+    conn.transaction(|conn| {
+        let previous_email: String = users.find(user_id).select(email).first(conn)?;
+        let updated_user: User = diesel::update(users.find(user_id))
+            .set(user)
+            .get_result(conn)?;
+        if !email_changed(&previous_email, &updated_user.email) {
+            return Ok((updated_user, false));
+        }
+        diesel::update(users.find(user_id))
+            .set(email_verified_at.eq(None::<chrono::DateTime<Utc>>))
+            .get_result(conn)
+            .map(|updated_user| (updated_user, true))
+    })
+}
+
+// whether the email has to be verified again. Any change counts, even of the case only,
+// since `verify_email` only accepts the exact address the link was sent to
+fn email_changed(previous_email: &str, new_email: &str) -> bool {
+    previous_email != new_email
+}
+
+// mark the email of a user as verified, if it's still the given one.
+// `None` if the user has been deleted or has changed their email since
+pub fn verify_email(
+    conn: &mut PooledConnection<ConnectionManager<PgConnection>>,
+    user_id: i32,
+    verified_email: &str,
+) -> diesel::QueryResult<Option<User>> {
+    use crate::models::schema::users::dsl::*;
+
+    diesel::update(
+        users.filter(
+            id.eq(user_id)
+                .and(email.eq(verified_email))
+                .and(deleted_at.is_null()),
+        ),
+    )
+    .set(email_verified_at.eq(Some(chrono::Utc::now())))
+    .get_result(conn)
+    .optional()
 }
 
 // delete a user by id, soft delete by setting deleted_at
@@ -286,3 +553,113 @@ fn test_verify_user() {
         .verify_password(pwd.as_bytes(), &hashed_password)
         .is_ok());
 }
+
+#[test]
+fn test_user_views() {
+    let user = User {
+        id: 1,
+        name: "elton".to_string(),
+        email: "elton@pwr.ink".to_string(),
+        avatar: None,
+        password: "$argon2id$v=19$m=19456,t=2,p=1$c2FsdA$aGFzaA".to_string(),
+        role: Role::User,
+        created_at: Some(chrono::Utc::now()),
+        modified_at: Some(chrono::Utc::now()),
+        deleted_at: Some(chrono::Utc::now()),
+        email_verified_at: None,
+    };
+
+    let view = serde_json::to_value(ScopedUserView::new(Role::User, user.clone())).unwrap();
+    assert_eq!(view["name"], "elton");
+    assert!(view.get("password").is_none());
+    assert!(view.get("deleted_at").is_none());
+    assert!(view["email_verified_at"].is_null());
+
+    let view = serde_json::to_value(ScopedUserView::new(Role::Admin, user)).unwrap();
+    assert_eq!(view["role"], "user");
+    assert!(view.get("password").is_none());
+    assert!(view["deleted_at"].is_string());
+}
+
+#[test]
+fn test_search_query_sort() {
+    use crate::models::schema::users::dsl::*;
+
+    let query: SearchQuery = serde_json::from_str(
+        r#"{"search_term": "elton", "sort": [{"sort_by": "name"}, {"sort_by": "created_at", "order_by": "desc"}], "page": 1, "page_size": 10}"#,
+    )
+    .unwrap();
+    let sql = diesel::debug_query::<Pg, _>(
+        &users
+            .select(id)
+            .into_boxed()
+            .then_order_by(query.sort[0].expression())
+            .then_order_by(query.sort[1].expression()),
+    )
+    .to_string();
+    assert!(sql.contains(r#"ORDER BY "users"."name" ASC , "users"."created_at" DESC"#));
+
+    // the sort of the previous api
+    let query: SearchQuery = serde_json::from_str(
+        r#"{"search_term": "elton", "sort_by": "email", "order_by": "DESC", "page": 1, "page_size": 10}"#,
+    )
+    .unwrap();
+    assert_eq!(
+        query.sort_specs(),
+        [SortSpec {
+            sort_by: SortColumn::Email,
+            order_by: SortOrder::Desc
+        }]
+    );
+    let query: SearchQuery = serde_json::from_str(
+        r#"{"search_term": "", "sort": [{"sort_by": "name"}], "sort_by": "email", "page": 1, "page_size": 10}"#,
+    )
+    .unwrap();
+    assert_eq!(query.sort_specs()[0].sort_by, SortColumn::Name);
+    let err = serde_json::from_str::<SearchQuery>(
+        r#"{"search_term": "", "sort_by": "password", "page": 1, "page_size": 10}"#,
+    )
+    .unwrap_err();
+    assert!(err.to_string().contains("unknown variant `password`"));
+
+    let err = serde_json::from_str::<SearchQuery>(
+        r#"{"search_term": "", "sort": [{"sort_by": "name; DROP TABLE users"}], "page": 1, "page_size": 10}"#,
+    )
+    .unwrap_err();
+    assert!(err
+        .to_string()
+        .contains("expected one of `id`, `name`, `email`"));
+
+    let err = serde_json::from_str::<SearchQuery>(
+        r#"{"search_term": "", "sort": [{"sort_by": "name", "order_by": "up"}], "page": 1, "page_size": 10}"#,
+    )
+    .unwrap_err();
+    assert!(err.to_string().contains("expected `asc` or `desc`"));
+}
+
+#[test]
+fn test_email_changed() {
+    assert!(!email_changed("elton@pwr.ink", "elton@pwr.ink"));
+    assert!(email_changed("elton@pwr.ink", "Elton@PWR.ink"));
+    assert!(email_changed("elton@pwr.ink", "elton@pwr.io"));
+}
+
+#[test]
+fn test_verify_password() {
+    let user = User {
+        id: 2,
+        name: "elton".to_string(),
+        email: "elton@pwr.ink".to_string(),
+        avatar: None,
+        password: hash_password("old-password").unwrap(),
+        role: Role::User,
+        created_at: None,
+        modified_at: None,
+        deleted_at: None,
+        email_verified_at: None,
+    };
+    assert!(verify_password(&user, "old-password").unwrap());
+    // a wrong old password doesn't let `change_password` set a new one
+    assert!(!verify_password(&user, "wrong-password").unwrap());
+    assert!(!verify_password(&user, "").unwrap());
+}
@@ -1,14 +1,10 @@
 use ::r2d2::Error;
 use diesel::prelude::*;
 use diesel::r2d2::{self, ConnectionManager};
-use dotenvy::dotenv;
 
 pub type DbPool = r2d2::Pool<ConnectionManager<PgConnection>>;
 
-pub fn new() -> Result<r2d2::Pool<ConnectionManager<PgConnection>>, Error> {
-    dotenv().ok();
-    let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-
+pub fn new(database_url: &str) -> Result<r2d2::Pool<ConnectionManager<PgConnection>>, Error> {
     let manager = ConnectionManager::<PgConnection>::new(database_url);
     r2d2::Pool::builder().build(manager)
 }
@@ -0,0 +1,180 @@
+use redis::{aio::ConnectionManager, AsyncCommands, ExistenceCheck, SetExpiry, SetOptions};
+
+use crate::{config::Config, errors::AppError};
+
+// Login attempts are counted per account and per ip in redis, the successful ones are given back.
+// Once an account or an ip reaches its allowed attempts, it's locked out, and each further
+// failure doubles the lockout.
+// The accounts are keyed by email, whether they exist or not, so a lockout doesn't tell it.
+
+// redis key of the failed login counter of an account or an ip
+fn failures_key(scope: &str, id: &str) -> String {
+    format!("login_failures:{}:{}", scope, id)
+}
+
+// redis key of the lockout of an account or an ip, it expires with the lockout
+fn lock_key(scope: &str, id: &str) -> String {
+    format!("login_lock:{}:{}", scope, id)
+}
+
+// the account of an email, the same however it's written
+fn account(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+/// the lockout in seconds after the given number of failures, `None` while attempts are left.
+/// It starts at `base` seconds once the allowed attempts are used up, and doubles with each failure up to `max`.
+fn lockout(failures: u64, allowed: u64, base: u64, max: u64) -> Option<u64> {
+    let over = failures.checked_sub(allowed)?;
+    let factor = 1u64.checked_shl(over.min(63) as u32).unwrap_or(u64::MAX);
+    Some(base.saturating_mul(factor).min(max))
+}
+
+/// A login attempt of an account from an ip. It's counted before the password is verified,
+/// so concurrent attempts can't all get through before the first failure is recorded.
+/// A failed attempt is simply dropped, it stays counted.
+pub struct Attempt {
+    conn: ConnectionManager,
+    account: String,
+    // the scopes and ids the attempt was counted for, and whether it locked them
+    counted: Vec<(&'static str, String, bool)>,
+}
+
+/// count a login attempt of the account and the ip, it's rejected with 429 while either is locked out.
+/// The last allowed attempt locks them right away, and the first one after a lockout locks them
+/// again for twice as long; a successful attempt gives its count back.
+pub async fn begin(
+    conn: &ConnectionManager,
+    config: &Config,
+    email: &str,
+    ip: Option<&str>,
+) -> Result<Attempt, AppError> {
+    let mut attempt = Attempt {
+        conn: conn.clone(),
+        account: account(email),
+        counted: Vec::new(),
+    };
+    let mut scopes = vec![(
+        "account",
+        attempt.account.clone(),
+        config.login_max_attempts,
+    )];
+    scopes.extend(ip.map(|ip| ("ip", ip.to_string(), config.login_ip_max_attempts)));
+
+    let mut conn = conn.clone();
+    for (scope, id, allowed) in scopes {
+        // the ttl is negative if there is no lock
+        let (attempts, ttl): (u64, i64) = redis::pipe()
+            .atomic()
+            .incr(failures_key(scope, &id), 1)
+            .expire(
+                failures_key(scope, &id),
+                config.login_failure_window as i64 * 60,
+            )
+            .ignore()
+            .ttl(lock_key(scope, &id))
+            .query_async(&mut conn)
+            .await?;
+
+        let lock_seconds = lockout(
+            attempts,
+            allowed,
+            config.login_backoff_base,
+            config.login_lockout_max * 60,
+        );
+        let mut allowed_now = ttl <= 0;
+        if let (true, Some(seconds)) = (allowed_now, lock_seconds) {
+            // after a lockout, only one of the concurrent attempts gets through and locks again
+            let mut options = SetOptions::default().with_expiration(SetExpiry::EX(seconds));
+            if attempts > allowed {
+                options = options.conditional_set(ExistenceCheck::NX);
+            }
+            let set: Option<String> = conn
+                .set_options(lock_key(scope, &id), attempts, options)
+                .await?;
+            allowed_now = set.is_some();
+            if allowed_now {
+                log::warn!(
+                    target: "security",
+                    "Login of {} {} locked out for {}s after {} attempts",
+                    scope,
+                    id,
+                    seconds,
+                    attempts
+                );
+            }
+        }
+
+        if !allowed_now {
+            // the rejected attempts aren't counted
+            let (retry_after,): (i64,) = redis::pipe()
+                .decr(failures_key(scope, &id), 1)
+                .ignore()
+                .ttl(lock_key(scope, &id))
+                .query_async(&mut conn)
+                .await?;
+            attempt.refund().await?;
+            return Err(AppError::TooManyRequests(retry_after.max(1) as u64));
+        }
+        attempt.counted.push((scope, id, lock_seconds.is_some()));
+    }
+    Ok(attempt)
+}
+
+impl Attempt {
+    /// give the attempt back, e.g. the password is right but the TOTP code is still required.
+    /// The locks it set are lifted, the previous failures are kept
+    pub async fn refund(&self) -> Result<(), AppError> {
+        if self.counted.is_empty() {
+            return Ok(());
+        }
+        let mut pipe = redis::pipe();
+        for (scope, id, locked) in &self.counted {
+            pipe.decr(failures_key(scope, id), 1).ignore();
+            if *locked {
+                pipe.del(lock_key(scope, id)).ignore();
+            }
+        }
+        pipe.query_async::<()>(&mut self.conn.clone()).await?;
+        Ok(())
+    }
+
+    /// the login succeeded, the failures of the account are forgotten
+    pub async fn succeed(&self) -> Result<(), AppError> {
+        self.refund().await?;
+        reset(&self.conn, &self.account).await
+    }
+}
+
+/// forget the failed logins of an account, after a successful login or when an admin unlocks it.
+/// The failures of the ip are kept, a valid login mustn't reset the attempts against other accounts.
+pub async fn reset(conn: &ConnectionManager, email: &str) -> Result<(), AppError> {
+    let account = account(email);
+    let mut conn = conn.clone();
+    conn.del::<_, ()>(&[
+        failures_key("account", &account),
+        lock_key("account", &account),
+    ])
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+#[test]
+fn test_lockout() {
+    // 5 attempts, then 30 seconds doubling up to 15 minutes
+    let lockout = |failures| lockout(failures, 5, 30, 900);
+    assert_eq!(lockout(1), None);
+    assert_eq!(lockout(4), None);
+    assert_eq!(lockout(5), Some(30));
+    assert_eq!(lockout(6), Some(60));
+    assert_eq!(lockout(8), Some(240));
+    assert_eq!(lockout(10), Some(900));
+    assert_eq!(lockout(200), Some(900));
+
+    assert_eq!(account(" Bob@Example.com"), "bob@example.com");
+    assert_eq!(
+        lock_key("account", &account("Bob@Example.com")),
+        "login_lock:account:bob@example.com"
+    );
+}
@@ -1,2 +1,5 @@
 pub mod database;
+pub mod login_attempts;
+pub mod rate_limit;
 pub mod redis;
+pub mod token_store;
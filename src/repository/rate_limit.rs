@@ -0,0 +1,123 @@
+use redis::{aio::ConnectionManager, AsyncCommands};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{config::RateLimitRule, errors::AppError};
+
+// The requests are counted in fixed windows in redis, and the sliding window is estimated from
+// the current window and the part of the previous one it still overlaps. It's close enough
+// to a real sliding window with two counters per client, and bursts at the window edges can't double the limit.
+
+// redis key of the request counter of a client in a window of a route group
+fn counter_key(rule: &RateLimitRule, client: &str, window: u64) -> String {
+    format!("rate_limit:{}:{}:{}", rule.path, client, window)
+}
+
+/// The quota of a client after a request, sent in the `RateLimit-*` headers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quota {
+    pub limit: u64,
+    pub remaining: u64,
+    // seconds until the current window ends, or until the next request is allowed when it's rejected
+    pub reset: u64,
+    pub allowed: bool,
+}
+
+/// the quota after a request counted in the current window, `elapsed` is the time spent in the window.
+/// The times are in milliseconds.
+fn quota(limit: u64, window: u64, elapsed: u64, previous: u64, current: u64) -> Quota {
+    let left = window - elapsed;
+    // the requests of the previous window still in the sliding window
+    let overlap = previous * left / window;
+    let used = overlap + current;
+    let seconds = |ms: u64| ms.div_ceil(1000).max(1);
+
+    if used <= limit {
+        return Quota {
+            limit,
+            remaining: limit - used,
+            reset: seconds(left),
+            allowed: true,
+        };
+    }
+    // the rejected request isn't counted, it's allowed again once enough of the previous window has slid out
+    let before = current.saturating_sub(1);
+    let retry = if before < limit {
+        left.saturating_sub((limit - before - 1) * window / previous.max(1))
+    } else {
+        // the current window is full, its requests must slide out during the next one
+        left + window - (limit - 1) * window / before
+    };
+    Quota {
+        limit,
+        remaining: 0,
+        reset: seconds(retry),
+        allowed: false,
+    }
+}
+
+/// count a request of the client against the limit of its route group
+pub async fn hit(
+    conn: &ConnectionManager,
+    rule: &RateLimitRule,
+    client: &str,
+) -> Result<Quota, AppError> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+    let window = rule.window * 1000;
+    let index = now / window;
+
+    let mut conn = conn.clone();
+    let (current, previous): (u64, Option<u64>) = redis::pipe()
+        .atomic()
+        .incr(counter_key(rule, client, index), 1)
+        // the counter is the previous window during the next one
+        .expire(counter_key(rule, client, index), rule.window as i64 * 2)
+        .ignore()
+        .get(counter_key(rule, client, index - 1))
+        .query_async(&mut conn)
+        .await?;
+
+    let quota = quota(
+        rule.limit,
+        window,
+        now % window,
+        previous.unwrap_or(0),
+        current,
+    );
+    if !quota.allowed {
+        conn.decr::<_, _, ()>(counter_key(rule, client, index), 1)
+            .await?;
+    }
+    Ok(quota)
+}
+
+#[cfg(test)]
+#[test]
+fn test_quota() {
+    // 10 requests per 60 seconds
+    let quota = |elapsed, previous, current| quota(10, 60_000, elapsed, previous, current);
+
+    let first = quota(0, 0, 1);
+    assert!(first.allowed);
+    assert_eq!((first.remaining, first.reset), (9, 60));
+
+    // half of the previous window is still in the sliding window
+    let half = quota(30_000, 10, 4);
+    assert!(half.allowed);
+    assert_eq!(half.remaining, 1);
+    let half = quota(30_000, 10, 6);
+    assert!(!half.allowed);
+    assert_eq!((half.remaining, half.reset), (0, 6));
+
+    // the current window alone is full, wait for the next one
+    let full = quota(45_000, 0, 11);
+    assert!(!full.allowed);
+    assert_eq!(full.reset, 21);
+
+    assert_eq!(
+        counter_key(&"/api/v1/users=10/60".parse().unwrap(), "user:1", 7),
+        "rate_limit:/api/v1/users:user:1:7"
+    );
+}
@@ -1,9 +1,19 @@
-use dotenvy::dotenv;
-use redis::{Client, RedisError};
+use redis::{
+    aio::{ConnectionManager, ConnectionManagerConfig},
+    Client, RedisError,
+};
+use std::time::Duration;
 
-pub fn new() -> Result<redis::Client, RedisError> {
-    dotenv().ok();
-    let redis_url = std::env::var("REDIS_URL").expect("REDIS_URL must be set");
+// an unreachable redis must fail the requests quickly, instead of hanging them,
+// so the stateless fallback can take over
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(1);
+const RESPONSE_TIMEOUT: Duration = Duration::from_millis(500);
 
-    Client::open(redis_url)
+// the connection manager multiplexes one connection and reconnects when it's lost
+pub async fn new(redis_url: &str) -> Result<ConnectionManager, RedisError> {
+    let client = Client::open(redis_url)?;
+    let config = ConnectionManagerConfig::new()
+        .set_connection_timeout(CONNECTION_TIMEOUT)
+        .set_response_timeout(RESPONSE_TIMEOUT);
+    ConnectionManager::new_with_config(client, config).await
 }
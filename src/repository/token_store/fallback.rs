@@ -0,0 +1,419 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use super::{timestamp, NewSession, Rotation, Session, TokenRotation, TokenStore};
+use crate::{config::Config, errors::AppError};
+
+// A revocation made while the token store was unavailable, replayed when it recovers.
+#[derive(Debug, Clone, PartialEq)]
+enum Revocation {
+    Session {
+        user_id: usize,
+        session_id: String,
+    },
+    User {
+        user_id: usize,
+        except: Option<String>,
+    },
+}
+
+// A revocation known to this process, the tokens it covers are rejected by the stateless fallback.
+// It's dropped once the tokens issued before it are too old for the fallback anyway.
+struct Denied {
+    revoked_at: usize,
+    // the session kept by a revocation of all the sessions of the user
+    except: Option<String>,
+}
+
+#[derive(Default)]
+struct State {
+    // when the current outage started, `None` while the token store is available
+    degraded_since: Option<usize>,
+    // the revoked sessions by id, with the id of their user
+    sessions: HashMap<String, (usize, Denied)>,
+    // the last revocation of all the sessions of a user
+    users: HashMap<usize, Denied>,
+    pending: Vec<Revocation>,
+}
+
+/// How the stateless fallback was used, served to admins by `GET /api/v1/metrics/fallback`.
+#[derive(Debug, Serialize)]
+pub struct FallbackMetrics {
+    pub degraded: bool,
+    pub degraded_since: Option<DateTime<Utc>>,
+    // number of times the token store became unavailable
+    pub outages: u64,
+    // access tokens accepted and rejected by the fallback
+    pub accepted: u64,
+    pub rejected: u64,
+    // revocations queued during an outage, and replayed after it
+    pub queued_revocations: u64,
+    pub synced_revocations: u64,
+    pub pending_revocations: usize,
+}
+
+/// A token store degrading to stateless verification while the wrapped store is unavailable.
+///
+/// Access tokens are then accepted by their signature and expiration, if they were issued recently
+/// and their session wasn't revoked by this process. Revocations made during the outage are kept
+/// in a local deny-list and replayed to the wrapped store once it's available again.
+/// The deny-list is local, a session revoked by another instance is accepted until the outage ends
+/// or its tokens are too old.
+pub struct FallbackTokenStore {
+    inner: Arc<dyn TokenStore>,
+    // seconds
+    max_age: usize,
+    state: Mutex<State>,
+    outages: AtomicU64,
+    accepted: AtomicU64,
+    rejected: AtomicU64,
+    queued: AtomicU64,
+    synced: AtomicU64,
+}
+
+fn now() -> usize {
+    Utc::now().timestamp().try_into().unwrap_or_default()
+}
+
+impl FallbackTokenStore {
+    pub fn new(inner: Arc<dyn TokenStore>, config: &Config) -> Self {
+        FallbackTokenStore {
+            inner,
+            max_age: config.stateless_fallback_max_age as usize * 60,
+            state: Mutex::default(),
+            outages: AtomicU64::new(0),
+            accepted: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+            queued: AtomicU64::new(0),
+            synced: AtomicU64::new(0),
+        }
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // the state with the revocations still covering tokens the fallback would accept
+    fn denied(&self, now: usize) -> std::sync::MutexGuard<'_, State> {
+        let mut state = self.state();
+        let max_age = self.max_age;
+        state
+            .sessions
+            .retain(|_, (_, denied)| denied.revoked_at + max_age > now);
+        state
+            .users
+            .retain(|_, denied| denied.revoked_at + max_age > now);
+        state
+    }
+
+    /// verify an access token without the token store, it returns the user id if the token
+    /// was issued at most the fallback max age ago and its session wasn't revoked.
+    /// The signature and expiration of the token must have been checked already.
+    pub fn verify(
+        &self,
+        user_id: usize,
+        session_id: &str,
+        issued_at: usize,
+        now: usize,
+    ) -> Option<usize> {
+        let state = self.denied(now);
+        let revoked = matches!(state.sessions.get(session_id), Some((owner, _)) if *owner == user_id)
+            || state.users.get(&user_id).is_some_and(|denied| {
+                denied.revoked_at >= issued_at && denied.except.as_deref() != Some(session_id)
+            });
+        if revoked || issued_at + self.max_age <= now {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        self.accepted.fetch_add(1, Ordering::Relaxed);
+        Some(user_id)
+    }
+
+    pub fn metrics(&self) -> FallbackMetrics {
+        let state = self.state();
+        FallbackMetrics {
+            degraded: state.degraded_since.is_some(),
+            degraded_since: state.degraded_since.map(timestamp),
+            outages: self.outages.load(Ordering::Relaxed),
+            accepted: self.accepted.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+            queued_revocations: self.queued.load(Ordering::Relaxed),
+            synced_revocations: self.synced.load(Ordering::Relaxed),
+            pending_revocations: state.pending.len(),
+        }
+    }
+
+    // record the result of a call to the wrapped store, an outage starts or ends with it
+    async fn observe<T>(&self, result: Result<T, AppError>) -> Result<T, AppError> {
+        match &result {
+            Err(AppError::ServiceUnavailable) => {
+                let mut state = self.state();
+                if state.degraded_since.is_none() {
+                    state.degraded_since = Some(now());
+                    self.outages.fetch_add(1, Ordering::Relaxed);
+                    log::warn!(
+                        "Token store is unavailable, access tokens are verified statelessly"
+                    );
+                }
+            }
+            Err(_) => {}
+            Ok(_) => self.recover().await,
+        }
+        result
+    }
+
+    // end the outage, the revocations queued during it are replayed
+    async fn recover(&self) {
+        let pending = {
+            let mut state = self.state();
+            if let Some(since) = state.degraded_since.take() {
+                log::info!(
+                    "Token store is available again after {}s, {} revocations to sync",
+                    now().saturating_sub(since),
+                    state.pending.len()
+                );
+            }
+            std::mem::take(&mut state.pending)
+        };
+
+        let mut pending = pending.into_iter();
+        while let Some(revocation) = pending.next() {
+            let result = match &revocation {
+                Revocation::Session {
+                    user_id,
+                    session_id,
+                } => self.inner.revoke(*user_id, session_id).await.map(|_| ()),
+                Revocation::User { user_id, except } => self
+                    .inner
+                    .revoke_all(*user_id, except.as_deref(), now())
+                    .await
+                    .map(|_| ()),
+            };
+            if let Err(e) = result {
+                // it's retried after the next successful call
+                log::error!("Failed to sync revocation {:?}: {}", revocation, e);
+                let mut state = self.state();
+                state.pending.push(revocation);
+                state.pending.extend(pending);
+                return;
+            }
+            self.synced.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // queue a revocation if the wrapped store is unavailable, it's applied by the fallback meanwhile
+    fn queue(&self, revocation: Revocation) {
+        self.queued.fetch_add(1, Ordering::Relaxed);
+        self.state().pending.push(revocation);
+    }
+}
+
+#[async_trait]
+impl TokenStore for FallbackTokenStore {
+    async fn save(&self, session: NewSession) -> Result<(), AppError> {
+        self.observe(self.inner.save(session).await).await
+    }
+
+    async fn lookup(
+        &self,
+        token_id: &str,
+        session_id: &str,
+        now: usize,
+    ) -> Result<Option<usize>, AppError> {
+        self.observe(self.inner.lookup(token_id, session_id, now).await)
+            .await
+    }
+
+    async fn rotate(&self, rotation: TokenRotation) -> Result<Rotation, AppError> {
+        self.observe(self.inner.rotate(rotation).await).await
+    }
+
+    // the revocations are always denied locally, the tokens they cover may be presented
+    // during a later outage
+    async fn revoke(&self, user_id: usize, session_id: &str) -> Result<Option<usize>, AppError> {
+        let now = now();
+        let denied = Denied {
+            revoked_at: now,
+            except: None,
+        };
+        self.denied(now)
+            .sessions
+            .insert(session_id.to_owned(), (user_id, denied));
+
+        match self
+            .observe(self.inner.revoke(user_id, session_id).await)
+            .await
+        {
+            Err(AppError::ServiceUnavailable) => {
+                self.queue(Revocation::Session {
+                    user_id,
+                    session_id: session_id.to_owned(),
+                });
+                Ok(Some(user_id))
+            }
+            result => result,
+        }
+    }
+
+    async fn revoke_all(
+        &self,
+        user_id: usize,
+        except: Option<&str>,
+        now: usize,
+    ) -> Result<usize, AppError> {
+        let denied = Denied {
+            revoked_at: now,
+            except: except.map(str::to_owned),
+        };
+        self.denied(now).users.insert(user_id, denied);
+
+        match self
+            .observe(self.inner.revoke_all(user_id, except, now).await)
+            .await
+        {
+            // the number of sessions isn't known until the revocation is synced
+            Err(AppError::ServiceUnavailable) => {
+                self.queue(Revocation::User {
+                    user_id,
+                    except: except.map(str::to_owned),
+                });
+                Ok(0)
+            }
+            result => result,
+        }
+    }
+
+    async fn list(&self, user_id: usize, now: usize) -> Result<Vec<Session>, AppError> {
+        self.observe(self.inner.list(user_id, now).await).await
+    }
+}
+
+// a memory store that can be taken down, to test the outages
+#[cfg(test)]
+#[derive(Default)]
+struct FlakyStore {
+    inner: super::MemoryTokenStore,
+    down: std::sync::atomic::AtomicBool,
+}
+
+#[cfg(test)]
+impl FlakyStore {
+    fn check(&self) -> Result<(), AppError> {
+        if self.down.load(Ordering::Relaxed) {
+            Err(AppError::ServiceUnavailable)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+#[async_trait]
+impl TokenStore for FlakyStore {
+    async fn save(&self, session: NewSession) -> Result<(), AppError> {
+        self.check()?;
+        self.inner.save(session).await
+    }
+
+    async fn lookup(
+        &self,
+        token_id: &str,
+        session_id: &str,
+        now: usize,
+    ) -> Result<Option<usize>, AppError> {
+        self.check()?;
+        self.inner.lookup(token_id, session_id, now).await
+    }
+
+    async fn rotate(&self, rotation: TokenRotation) -> Result<Rotation, AppError> {
+        self.check()?;
+        self.inner.rotate(rotation).await
+    }
+
+    async fn revoke(&self, user_id: usize, session_id: &str) -> Result<Option<usize>, AppError> {
+        self.check()?;
+        self.inner.revoke(user_id, session_id).await
+    }
+
+    async fn revoke_all(
+        &self,
+        user_id: usize,
+        except: Option<&str>,
+        now: usize,
+    ) -> Result<usize, AppError> {
+        self.check()?;
+        self.inner.revoke_all(user_id, except, now).await
+    }
+
+    async fn list(&self, user_id: usize, now: usize) -> Result<Vec<Session>, AppError> {
+        self.check()?;
+        self.inner.list(user_id, now).await
+    }
+}
+
+#[cfg(test)]
+#[ntex::test]
+async fn test_stateless_fallback() {
+    let flaky = Arc::new(FlakyStore::default());
+    let store = FallbackTokenStore::new(flaky.clone(), &crate::config::test_config());
+    let now = now();
+    for (id, user_id) in [("a", 1), ("b", 1), ("c", 2)] {
+        store
+            .save(NewSession {
+                id: id.to_string(),
+                user_id,
+                access_token_id: format!("{}-access", id),
+                refresh_token_id: format!("{}-refresh", id),
+                access_token_max_age: 900,
+                refresh_token_max_age: 86400,
+                ip: None,
+                user_agent: None,
+                now,
+            })
+            .await
+            .unwrap();
+    }
+
+    flaky.down.store(true, Ordering::Relaxed);
+    assert!(matches!(
+        store.lookup("a-access", "a", now).await,
+        Err(AppError::ServiceUnavailable)
+    ));
+    assert!(store.metrics().degraded);
+    // only the recent tokens are accepted, the max age is 5 minutes
+    assert_eq!(store.verify(1, "a", now - 60, now), Some(1));
+    assert_eq!(store.verify(1, "a", now - 300, now), None);
+
+    // the revocations made during the outage are applied locally
+    assert_eq!(store.revoke(1, "a").await.unwrap(), Some(1));
+    assert_eq!(store.verify(1, "a", now, now), None);
+    assert_eq!(store.verify(1, "b", now, now), Some(1));
+    assert_eq!(store.revoke_all(2, None, now).await.unwrap(), 0);
+    assert_eq!(store.verify(2, "c", now, now), None);
+    // tokens issued after the revocation aren't covered by it
+    assert_eq!(store.verify(2, "d", now + 2, now + 2), Some(2));
+
+    let metrics = store.metrics();
+    assert_eq!(metrics.outages, 1);
+    assert_eq!(metrics.accepted, 3);
+    assert_eq!(metrics.rejected, 3);
+    assert_eq!(metrics.pending_revocations, 2);
+
+    // they are synced once the store is available again
+    flaky.down.store(false, Ordering::Relaxed);
+    assert_eq!(store.lookup("b-access", "b", now).await.unwrap(), Some(1));
+    assert_eq!(store.lookup("a-access", "a", now).await.unwrap(), None);
+    assert_eq!(store.lookup("c-access", "c", now).await.unwrap(), None);
+    let metrics = store.metrics();
+    assert!(!metrics.degraded);
+    assert_eq!(metrics.synced_revocations, 2);
+    assert_eq!(metrics.pending_revocations, 0);
+
+    // the local deny-list expires with the fallback max age
+    assert_eq!(store.verify(1, "a", now, now + 300), None);
+    assert_eq!(store.verify(1, "a", now + 10, now + 302), Some(1));
+}
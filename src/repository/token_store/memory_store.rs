@@ -0,0 +1,243 @@
+use async_trait::async_trait;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+use super::{timestamp, NewSession, Rotation, Session, TokenRotation, TokenStore};
+use crate::errors::AppError;
+
+// a session and the expiration times of its tokens
+struct Record {
+    user_id: usize,
+    access_token_id: String,
+    access_expires_at: usize,
+    refresh_token_id: String,
+    // the session expires with its refresh token
+    expires_at: usize,
+    created_at: usize,
+    last_used_at: usize,
+    ip: Option<String>,
+    user_agent: Option<String>,
+    // the refresh token ids already rotated in the session
+    rotated: HashSet<String>,
+}
+
+/// The sessions in the process memory, they are lost on restart and not shared between processes.
+/// It's meant for tests and local development.
+#[derive(Default)]
+pub struct MemoryTokenStore {
+    sessions: Mutex<HashMap<String, Record>>,
+}
+
+impl MemoryTokenStore {
+    // the active sessions, the expired ones are dropped first
+    fn sessions(&self, now: usize) -> std::sync::MutexGuard<'_, HashMap<String, Record>> {
+        let mut sessions = self.sessions.lock().unwrap_or_else(|e| e.into_inner());
+        sessions.retain(|_, record| record.expires_at > now);
+        sessions
+    }
+}
+
+#[async_trait]
+impl TokenStore for MemoryTokenStore {
+    async fn save(&self, session: NewSession) -> Result<(), AppError> {
+        let record = Record {
+            user_id: session.user_id,
+            access_token_id: session.access_token_id,
+            access_expires_at: session.now + session.access_token_max_age as usize,
+            refresh_token_id: session.refresh_token_id,
+            expires_at: session.now + session.refresh_token_max_age as usize,
+            created_at: session.now,
+            last_used_at: session.now,
+            ip: session.ip,
+            user_agent: session.user_agent,
+            rotated: HashSet::new(),
+        };
+        self.sessions(session.now).insert(session.id, record);
+        Ok(())
+    }
+
+    async fn lookup(
+        &self,
+        token_id: &str,
+        session_id: &str,
+        now: usize,
+    ) -> Result<Option<usize>, AppError> {
+        let mut sessions = self.sessions(now);
+        let Some(record) = sessions.get_mut(session_id) else {
+            return Ok(None);
+        };
+        let active = (record.access_token_id == token_id && record.access_expires_at > now)
+            || record.refresh_token_id == token_id;
+        if !active {
+            return Ok(None);
+        }
+        record.last_used_at = now;
+        Ok(Some(record.user_id))
+    }
+
+    async fn rotate(&self, rotation: TokenRotation) -> Result<Rotation, AppError> {
+        let mut sessions = self.sessions(rotation.now);
+        let Some(record) = sessions.get_mut(&rotation.session_id) else {
+            return Ok(Rotation::Unknown);
+        };
+        if record.refresh_token_id != rotation.refresh_token_id {
+            return Ok(if record.rotated.contains(&rotation.refresh_token_id) {
+                Rotation::Reused
+            } else {
+                Rotation::Unknown
+            });
+        }
+
+        let refresh_token_id =
+            std::mem::replace(&mut record.refresh_token_id, rotation.new_refresh_token_id);
+        record.rotated.insert(refresh_token_id);
+        record.access_token_id = rotation.new_access_token_id;
+        record.access_expires_at = rotation.now + rotation.access_token_max_age as usize;
+        record.expires_at = rotation.now + rotation.refresh_token_max_age as usize;
+        record.last_used_at = rotation.now;
+        Ok(Rotation::Rotated)
+    }
+
+    async fn revoke(&self, user_id: usize, session_id: &str) -> Result<Option<usize>, AppError> {
+        let mut sessions = self.sessions.lock().unwrap_or_else(|e| e.into_inner());
+        match sessions.get(session_id) {
+            Some(record) if record.user_id == user_id => {
+                sessions.remove(session_id);
+                Ok(Some(user_id))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    async fn revoke_all(
+        &self,
+        user_id: usize,
+        except: Option<&str>,
+        now: usize,
+    ) -> Result<usize, AppError> {
+        let mut sessions = self.sessions(now);
+        let count = sessions.len();
+        sessions.retain(|id, record| record.user_id != user_id || Some(id.as_str()) == except);
+        Ok(count - sessions.len())
+    }
+
+    async fn list(&self, user_id: usize, now: usize) -> Result<Vec<Session>, AppError> {
+        let mut sessions: Vec<Session> = self
+            .sessions(now)
+            .iter()
+            .filter(|(_, record)| record.user_id == user_id)
+            .map(|(id, record)| Session {
+                id: id.clone(),
+                created_at: Some(timestamp(record.created_at)),
+                last_used_at: Some(timestamp(record.last_used_at)),
+                ip: record.ip.clone(),
+                user_agent: record.user_agent.clone(),
+            })
+            .collect();
+        sessions.sort_by_key(|session| std::cmp::Reverse(session.created_at));
+        Ok(sessions)
+    }
+}
+
+#[cfg(test)]
+fn new_session(id: &str, user_id: usize, now: usize) -> NewSession {
+    NewSession {
+        id: id.to_string(),
+        user_id,
+        access_token_id: format!("{}-access", id),
+        refresh_token_id: format!("{}-refresh", id),
+        access_token_max_age: 900,
+        refresh_token_max_age: 86400,
+        ip: Some("127.0.0.1".to_string()),
+        user_agent: None,
+        now,
+    }
+}
+
+#[cfg(test)]
+#[ntex::test]
+async fn test_memory_store_sessions() {
+    let store = MemoryTokenStore::default();
+    let now = 1711085997;
+    store.save(new_session("a", 1, now)).await.unwrap();
+    store.save(new_session("b", 1, now + 10)).await.unwrap();
+    store.save(new_session("c", 2, now)).await.unwrap();
+
+    // the access token expires before its session
+    assert_eq!(
+        store.lookup("a-access", "a", now + 20).await.unwrap(),
+        Some(1)
+    );
+    assert_eq!(store.lookup("a-access", "b", now + 20).await.unwrap(), None);
+    assert_eq!(
+        store.lookup("a-access", "a", now + 900).await.unwrap(),
+        None
+    );
+
+    let sessions = store.list(1, now + 20).await.unwrap();
+    let ids: Vec<&str> = sessions.iter().map(|session| session.id.as_str()).collect();
+    assert_eq!(ids, ["b", "a"]);
+    assert_eq!(
+        sessions[1].last_used_at.unwrap().timestamp(),
+        now as i64 + 20
+    );
+    assert_eq!(sessions[1].ip.as_deref(), Some("127.0.0.1"));
+
+    // a session of another user isn't revoked
+    assert_eq!(store.revoke(1, "c").await.unwrap(), None);
+    assert_eq!(store.revoke(1, "a").await.unwrap(), Some(1));
+    assert_eq!(store.revoke(1, "a").await.unwrap(), None);
+    store.save(new_session("d", 1, now)).await.unwrap();
+    assert_eq!(store.revoke_all(1, Some("b"), now).await.unwrap(), 1);
+    // an expired session isn't counted as revoked
+    store.save(new_session("e", 1, now - 86400)).await.unwrap();
+    assert_eq!(store.revoke_all(1, None, now).await.unwrap(), 1);
+    assert!(store.list(1, now).await.unwrap().is_empty());
+    assert_eq!(store.list(2, now).await.unwrap().len(), 1);
+
+    // the session expires with its refresh token
+    assert!(store.list(2, now + 86400).await.unwrap().is_empty());
+}
+
+#[cfg(test)]
+#[ntex::test]
+async fn test_memory_store_rotation() {
+    let store = MemoryTokenStore::default();
+    let now = 1711085997;
+    store.save(new_session("a", 1, now)).await.unwrap();
+
+    let rotation = |refresh_token_id: &str, new: &str| TokenRotation {
+        session_id: "a".to_string(),
+        user_id: 1,
+        refresh_token_id: refresh_token_id.to_string(),
+        new_access_token_id: format!("{}-access", new),
+        new_refresh_token_id: format!("{}-refresh", new),
+        access_token_max_age: 900,
+        refresh_token_max_age: 86400,
+        now: now + 60,
+    };
+    assert_eq!(
+        store.rotate(rotation("a-refresh", "a2")).await.unwrap(),
+        Rotation::Rotated
+    );
+    // the old token pair stops working at once
+    assert_eq!(store.lookup("a-access", "a", now + 60).await.unwrap(), None);
+    assert_eq!(
+        store.lookup("a2-access", "a", now + 60).await.unwrap(),
+        Some(1)
+    );
+
+    assert_eq!(
+        store.rotate(rotation("a-refresh", "a3")).await.unwrap(),
+        Rotation::Reused
+    );
+    assert_eq!(
+        store.rotate(rotation("b-refresh", "a3")).await.unwrap(),
+        Rotation::Unknown
+    );
+    store.revoke(1, "a").await.unwrap();
+    assert_eq!(
+        store.rotate(rotation("a2-refresh", "a3")).await.unwrap(),
+        Rotation::Unknown
+    );
+}
@@ -0,0 +1,136 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use redis::aio::ConnectionManager;
+use serde::Serialize;
+use std::sync::Arc;
+
+use crate::{
+    config::{Config, ConfigError, TokenStoreKind},
+    errors::AppError,
+    repository::database::DbPool,
+};
+
+mod fallback;
+mod memory_store;
+mod postgres_store;
+mod redis_store;
+
+pub use fallback::{FallbackMetrics, FallbackTokenStore};
+pub use memory_store::MemoryTokenStore;
+pub use postgres_store::PostgresTokenStore;
+pub use redis_store::RedisTokenStore;
+
+// Every login starts a session, its record links the access and refresh token ids issued to it,
+// so they are always rotated and revoked together. The stores change the record and the token ids atomically.
+
+/// A new session and its first token pair, the lifetimes are in seconds.
+#[derive(Debug, Clone)]
+pub struct NewSession {
+    pub id: String,
+    pub user_id: usize,
+    pub access_token_id: String,
+    pub refresh_token_id: String,
+    pub access_token_max_age: u64,
+    // the session lives as long as the refresh token, so an expired access token can still be used to log out
+    pub refresh_token_max_age: u64,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub now: usize,
+}
+
+/// The token pair replacing the current one of a session, the lifetimes are in seconds.
+#[derive(Debug, Clone)]
+pub struct TokenRotation {
+    pub session_id: String,
+    pub user_id: usize,
+    // the refresh token presented by the client, it must be the current one of the session
+    pub refresh_token_id: String,
+    pub new_access_token_id: String,
+    pub new_refresh_token_id: String,
+    pub access_token_max_age: u64,
+    pub refresh_token_max_age: u64,
+    pub now: usize,
+}
+
+/// The outcome of a token rotation.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rotation {
+    Rotated,
+    // the refresh token was already rotated, it has been stolen (or the client is broken)
+    Reused,
+    // the session has expired or been revoked
+    Unknown,
+}
+
+/// An active session of a user.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Session {
+    pub id: String,
+    // sessions started before their metadata was recorded have none
+    pub created_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+/// Where the sessions and their token ids are kept, picked by `TOKEN_STORE`.
+/// The times are unix timestamps in seconds, passed in so the stores agree with the token clock.
+#[async_trait]
+pub trait TokenStore: Send + Sync {
+    /// save a new session with its first token pair
+    async fn save(&self, session: NewSession) -> Result<(), AppError>;
+
+    /// get the user id of an active token of the session and record the use of the session,
+    /// `None` if the token has expired or been revoked
+    async fn lookup(
+        &self,
+        token_id: &str,
+        session_id: &str,
+        now: usize,
+    ) -> Result<Option<usize>, AppError>;
+
+    /// replace the token pair of a session, if the presented refresh token is its current one
+    async fn rotate(&self, rotation: TokenRotation) -> Result<Rotation, AppError>;
+
+    /// delete a session of the user and its token ids,
+    /// returns the user id, or `None` if it was already gone or belongs to another user
+    async fn revoke(&self, user_id: usize, session_id: &str) -> Result<Option<usize>, AppError>;
+
+    /// delete the sessions of a user but the kept one,
+    /// returns the number of deleted sessions that were still active at `now`
+    async fn revoke_all(
+        &self,
+        user_id: usize,
+        except: Option<&str>,
+        now: usize,
+    ) -> Result<usize, AppError>;
+
+    /// the active sessions of a user, the most recently started first
+    async fn list(&self, user_id: usize, now: usize) -> Result<Vec<Session>, AppError>;
+}
+
+/// set up the token store configured by `TOKEN_STORE`,
+/// the redis store needs the connection, `REDIS_URL` is required with it.
+pub fn new(
+    config: &Config,
+    pool: &DbPool,
+    redis: Option<ConnectionManager>,
+) -> Result<Arc<dyn TokenStore>, ConfigError> {
+    Ok(match config.token_store {
+        TokenStoreKind::Redis => match redis {
+            Some(conn) => Arc::new(RedisTokenStore::new(conn)),
+            None => {
+                return Err(ConfigError(vec![
+                    "REDIS_URL must be set for the redis token store".to_string(),
+                ]))
+            }
+        },
+        TokenStoreKind::Postgres => Arc::new(PostgresTokenStore::new(pool.clone())),
+        TokenStoreKind::Memory => Arc::new(MemoryTokenStore::default()),
+    })
+}
+
+// a unix timestamp as a date, out of range timestamps are the epoch
+fn timestamp(secs: usize) -> DateTime<Utc> {
+    DateTime::from_timestamp(secs as i64, 0).unwrap_or_default()
+}
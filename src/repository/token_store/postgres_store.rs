@@ -0,0 +1,268 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use ntex::web;
+
+use super::{timestamp, NewSession, Rotation, Session, TokenRotation, TokenStore};
+use crate::{
+    errors::AppError,
+    models::schema::{rotated_tokens, sessions},
+    repository::database::DbPool,
+};
+
+#[derive(Insertable)]
+#[diesel(table_name = sessions)]
+struct NewRecord {
+    id: String,
+    user_id: i32,
+    access_token_id: String,
+    access_expires_at: DateTime<Utc>,
+    refresh_token_id: String,
+    expires_at: DateTime<Utc>,
+    created_at: DateTime<Utc>,
+    last_used_at: DateTime<Utc>,
+    ip: Option<String>,
+    user_agent: Option<String>,
+}
+
+#[derive(Queryable, Selectable)]
+#[diesel(table_name = sessions)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+struct SessionRecord {
+    id: String,
+    created_at: DateTime<Utc>,
+    last_used_at: DateTime<Utc>,
+    ip: Option<String>,
+    user_agent: Option<String>,
+}
+
+/// The sessions in the `sessions` table, for deployments without redis.
+/// Expired sessions are ignored, and deleted when their user logs in again.
+pub struct PostgresTokenStore {
+    pool: DbPool,
+}
+
+impl PostgresTokenStore {
+    pub fn new(pool: DbPool) -> Self {
+        PostgresTokenStore { pool }
+    }
+
+    // run the queries on a pooled connection, outside of the async workers
+    async fn run<T, F>(&self, query: F) -> Result<T, AppError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut PgConnection) -> QueryResult<T> + Send + 'static,
+    {
+        let pool = self.pool.clone();
+        web::block(move || {
+            // obtaining a connection from the pool is blocking as well
+            let mut conn = pool.get().map_err(|e| {
+                log::error!("Failed to get db connection: {}", e);
+                AppError::ServiceUnavailable
+            })?;
+            query(&mut conn).map_err(|e| {
+                log::error!("Token store query failed: {}", e);
+                AppError::InternalServerError(e.to_string())
+            })
+        })
+        .await
+        .map_err(|e| match e {
+            web::error::BlockingError::Error(e) => e,
+            web::error::BlockingError::Canceled => {
+                AppError::InternalServerError("Token store query canceled".to_string())
+            }
+        })
+    }
+}
+
+#[async_trait]
+impl TokenStore for PostgresTokenStore {
+    async fn save(&self, session: NewSession) -> Result<(), AppError> {
+        let now = timestamp(session.now);
+        let record = NewRecord {
+            id: session.id,
+            user_id: session.user_id as i32,
+            access_token_id: session.access_token_id,
+            access_expires_at: timestamp(session.now + session.access_token_max_age as usize),
+            refresh_token_id: session.refresh_token_id,
+            expires_at: timestamp(session.now + session.refresh_token_max_age as usize),
+            created_at: now,
+            last_used_at: now,
+            ip: session.ip,
+            user_agent: session.user_agent,
+        };
+        self.run(move |conn| {
+            conn.transaction(|conn| {
+                diesel::delete(
+                    sessions::table.filter(
+                        sessions::user_id
+                            .eq(record.user_id)
+                            .and(sessions::expires_at.le(now)),
+                    ),
+                )
+                .execute(conn)?;
+                diesel::insert_into(sessions::table)
+                    .values(&record)
+                    .execute(conn)
+                    .map(|_| ())
+            })
+        })
+        .await
+    }
+
+    async fn lookup(
+        &self,
+        token_id: &str,
+        session_id: &str,
+        now: usize,
+    ) -> Result<Option<usize>, AppError> {
+        let (token_id, session_id, now) =
+            (token_id.to_owned(), session_id.to_owned(), timestamp(now));
+        let user_id = self
+            .run(move |conn| {
+                diesel::update(
+                    sessions::table.filter(
+                        sessions::id.eq(session_id).and(
+                            sessions::access_token_id
+                                .eq(&token_id)
+                                .and(sessions::access_expires_at.gt(now))
+                                .or(sessions::refresh_token_id
+                                    .eq(&token_id)
+                                    .and(sessions::expires_at.gt(now))),
+                        ),
+                    ),
+                )
+                .set(sessions::last_used_at.eq(now))
+                .returning(sessions::user_id)
+                .get_result::<i32>(conn)
+                .optional()
+            })
+            .await?;
+        Ok(user_id.map(|user_id| user_id as usize))
+    }
+
+    async fn rotate(&self, rotation: TokenRotation) -> Result<Rotation, AppError> {
+        let now = timestamp(rotation.now);
+        self.run(move |conn| {
+            conn.transaction(|conn| {
+                // the row is only updated if the presented refresh token is still the current one
+                let rotated = diesel::update(
+                    sessions::table.filter(
+                        sessions::id
+                            .eq(&rotation.session_id)
+                            .and(sessions::refresh_token_id.eq(&rotation.refresh_token_id))
+                            .and(sessions::expires_at.gt(now)),
+                    ),
+                )
+                .set((
+                    sessions::access_token_id.eq(&rotation.new_access_token_id),
+                    sessions::access_expires_at.eq(timestamp(
+                        rotation.now + rotation.access_token_max_age as usize,
+                    )),
+                    sessions::refresh_token_id.eq(&rotation.new_refresh_token_id),
+                    sessions::expires_at.eq(timestamp(
+                        rotation.now + rotation.refresh_token_max_age as usize,
+                    )),
+                    sessions::last_used_at.eq(now),
+                ))
+                .execute(conn)?;
+                if rotated == 1 {
+                    diesel::insert_into(rotated_tokens::table)
+                        .values((
+                            rotated_tokens::token_id.eq(&rotation.refresh_token_id),
+                            rotated_tokens::session_id.eq(&rotation.session_id),
+                        ))
+                        .execute(conn)?;
+                    return Ok(Rotation::Rotated);
+                }
+
+                let reused = diesel::select(diesel::dsl::exists(
+                    rotated_tokens::table.filter(
+                        rotated_tokens::token_id
+                            .eq(&rotation.refresh_token_id)
+                            .and(rotated_tokens::session_id.eq(&rotation.session_id)),
+                    ),
+                ))
+                .get_result::<bool>(conn)?;
+                Ok(if reused {
+                    Rotation::Reused
+                } else {
+                    Rotation::Unknown
+                })
+            })
+        })
+        .await
+    }
+
+    async fn revoke(&self, user_id: usize, session_id: &str) -> Result<Option<usize>, AppError> {
+        let session_id = session_id.to_owned();
+        let revoked = self
+            .run(move |conn| {
+                diesel::delete(
+                    sessions::table.filter(
+                        sessions::id
+                            .eq(session_id)
+                            .and(sessions::user_id.eq(user_id as i32)),
+                    ),
+                )
+                .execute(conn)
+            })
+            .await?;
+        Ok((revoked > 0).then_some(user_id))
+    }
+
+    async fn revoke_all(
+        &self,
+        user_id: usize,
+        except: Option<&str>,
+        now: usize,
+    ) -> Result<usize, AppError> {
+        let except = except.unwrap_or_default().to_owned();
+        let now = timestamp(now);
+        // the expired sessions are deleted as well, but they aren't counted
+        let expires_at: Vec<DateTime<Utc>> = self
+            .run(move |conn| {
+                diesel::delete(
+                    sessions::table.filter(
+                        sessions::user_id
+                            .eq(user_id as i32)
+                            .and(sessions::id.ne(except)),
+                    ),
+                )
+                .returning(sessions::expires_at)
+                .get_results(conn)
+            })
+            .await?;
+        Ok(expires_at
+            .into_iter()
+            .filter(|expires_at| *expires_at > now)
+            .count())
+    }
+
+    async fn list(&self, user_id: usize, now: usize) -> Result<Vec<Session>, AppError> {
+        let now = timestamp(now);
+        let records = self
+            .run(move |conn| {
+                sessions::table
+                    .filter(
+                        sessions::user_id
+                            .eq(user_id as i32)
+                            .and(sessions::expires_at.gt(now)),
+                    )
+                    .order(sessions::created_at.desc())
+                    .select(SessionRecord::as_select())
+                    .load(conn)
+            })
+            .await?;
+        Ok(records
+            .into_iter()
+            .map(|record| Session {
+                id: record.id,
+                created_at: Some(record.created_at),
+                last_used_at: Some(record.last_used_at),
+                ip: record.ip,
+                user_agent: record.user_agent,
+            })
+            .collect())
+    }
+}
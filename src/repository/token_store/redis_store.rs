@@ -0,0 +1,325 @@
+use async_trait::async_trait;
+use chrono::DateTime;
+use redis::{aio::ConnectionManager, AsyncCommands, Script};
+use std::collections::HashMap;
+use std::sync::LazyLock;
+
+use super::{NewSession, Rotation, Session, TokenRotation, TokenStore};
+use crate::errors::AppError;
+
+// Each token id maps to the user id until the token expires, the session record links both
+// token ids, and the session is recorded in the user's session set, so all sessions can be
+// revoked at once. The scripts below need a standalone redis (not a cluster).
+
+// redis key of the session record, a hash of `user_id`, `access_token_id`, `refresh_token_id`
+// and the metadata shown to the user: `created_at`, `last_used_at`, `ip` and `user_agent`
+fn session_key(session_id: &str) -> String {
+    format!("session:{}", session_id)
+}
+
+// redis key of the set holding the session ids of a user
+fn user_sessions_key(user_id: usize) -> String {
+    format!("user_sessions:{}", user_id)
+}
+
+// redis key of the set holding the refresh token ids already rotated in a session
+fn rotated_tokens_key(session_id: &str) -> String {
+    format!("rotated_tokens:{}", session_id)
+}
+
+// rotate the token pair of a session, if the presented refresh token is its current one.
+// KEYS: session, rotated tokens, user sessions
+// ARGV: presented refresh token id, new access token id, new refresh token id, access ttl, refresh ttl, now
+// returns 1 when rotated, 0 when the refresh token was already rotated, -1 when it's unknown
+static ROTATE_SESSION: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        r"
+        local current = redis.call('HGET', KEYS[1], 'refresh_token_id')
+        if current ~= ARGV[1] or redis.call('EXISTS', ARGV[1]) == 0 then
+            if redis.call('SISMEMBER', KEYS[2], ARGV[1]) == 1 then
+                return 0
+            end
+            return -1
+        end
+        local user_id = redis.call('HGET', KEYS[1], 'user_id')
+        local access = redis.call('HGET', KEYS[1], 'access_token_id')
+        redis.call('DEL', access, ARGV[1])
+        redis.call('SET', ARGV[2], user_id, 'EX', ARGV[4])
+        redis.call('SET', ARGV[3], user_id, 'EX', ARGV[5])
+        redis.call('HSET', KEYS[1], 'access_token_id', ARGV[2], 'refresh_token_id', ARGV[3], 'last_used_at', ARGV[6])
+        redis.call('EXPIRE', KEYS[1], ARGV[5])
+        redis.call('SADD', KEYS[2], ARGV[1])
+        redis.call('EXPIRE', KEYS[2], ARGV[5])
+        redis.call('EXPIRE', KEYS[3], ARGV[5])
+        return 1
+        ",
+    )
+});
+
+// delete a session record of the user and its token ids.
+// KEYS: session, user sessions
+// ARGV: session id, user id
+// returns the user id of the session, nil if it was already gone or belongs to another user
+static REVOKE_SESSION: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        r"
+        local session = redis.call('HMGET', KEYS[1], 'user_id', 'access_token_id', 'refresh_token_id')
+        redis.call('SREM', KEYS[2], ARGV[1])
+        if session[1] ~= ARGV[2] then
+            return false
+        end
+        redis.call('DEL', KEYS[1])
+        for i = 2, 3 do
+            if session[i] then
+                redis.call('DEL', session[i])
+            end
+        end
+        return session[1]
+        ",
+    )
+});
+
+// delete the sessions of a user and their token ids, except the kept one.
+// KEYS: user sessions
+// ARGV: prefix of the session keys, id of the kept session (empty to delete all)
+// returns the number of deleted sessions that were still active, with their refresh token
+static REVOKE_USER_SESSIONS: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        r"
+        local sessions = redis.call('SMEMBERS', KEYS[1])
+        local revoked = 0
+        for _, session_id in ipairs(sessions) do
+            if session_id ~= ARGV[2] then
+                local key = ARGV[1] .. session_id
+                local ids = redis.call('HMGET', key, 'access_token_id', 'refresh_token_id')
+                if ids[2] and redis.call('EXISTS', ids[2]) == 1 then
+                    revoked = revoked + 1
+                end
+                redis.call('DEL', key)
+                for i = 1, 2 do
+                    if ids[i] then
+                        redis.call('DEL', ids[i])
+                    end
+                end
+                redis.call('SREM', KEYS[1], session_id)
+            end
+        end
+        return revoked
+        ",
+    )
+});
+
+// get the user id of a token and record the use in its session.
+// KEYS: token id, session
+// ARGV: now
+// returns the user id, nil if the token has expired or been revoked
+static TOUCH_SESSION: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        r"
+        local user_id = redis.call('GET', KEYS[1])
+        if user_id and redis.call('EXISTS', KEYS[2]) == 1 then
+            redis.call('HSET', KEYS[2], 'last_used_at', ARGV[1])
+        end
+        return user_id
+        ",
+    )
+});
+
+/// The sessions in redis, the keys expire with the tokens.
+pub struct RedisTokenStore {
+    // shared by all the workers, it reconnects automatically
+    conn: ConnectionManager,
+}
+
+impl RedisTokenStore {
+    pub fn new(conn: ConnectionManager) -> Self {
+        RedisTokenStore { conn }
+    }
+}
+
+// a session from its redis record
+fn session_from_record(id: String, mut record: HashMap<String, String>) -> Session {
+    let mut timestamp = |field: &str| {
+        record
+            .remove(field)
+            .and_then(|value| value.parse().ok())
+            .and_then(|secs| DateTime::from_timestamp(secs, 0))
+    };
+    Session {
+        id,
+        created_at: timestamp("created_at"),
+        last_used_at: timestamp("last_used_at"),
+        ip: record.remove("ip"),
+        user_agent: record.remove("user_agent"),
+    }
+}
+
+#[async_trait]
+impl TokenStore for RedisTokenStore {
+    async fn save(&self, session: NewSession) -> Result<(), AppError> {
+        let now = session.now.to_string();
+        let mut record = vec![
+            ("user_id", session.user_id.to_string()),
+            ("access_token_id", session.access_token_id.clone()),
+            ("refresh_token_id", session.refresh_token_id.clone()),
+            ("created_at", now.clone()),
+            ("last_used_at", now),
+        ];
+        record.extend(session.ip.map(|ip| ("ip", ip)));
+        record.extend(
+            session
+                .user_agent
+                .map(|user_agent| ("user_agent", user_agent)),
+        );
+
+        let mut conn = self.conn.clone();
+        let refresh_token_max_age = session.refresh_token_max_age as i64;
+        redis::pipe()
+            .atomic()
+            .set_ex(
+                &session.access_token_id,
+                session.user_id,
+                session.access_token_max_age,
+            )
+            .ignore()
+            .set_ex(
+                &session.refresh_token_id,
+                session.user_id,
+                session.refresh_token_max_age,
+            )
+            .ignore()
+            .hset_multiple(session_key(&session.id), &record)
+            .ignore()
+            .expire(session_key(&session.id), refresh_token_max_age)
+            .ignore()
+            .sadd(user_sessions_key(session.user_id), &session.id)
+            .ignore()
+            .expire(user_sessions_key(session.user_id), refresh_token_max_age)
+            .ignore()
+            .query_async::<()>(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn lookup(
+        &self,
+        token_id: &str,
+        session_id: &str,
+        now: usize,
+    ) -> Result<Option<usize>, AppError> {
+        let mut conn = self.conn.clone();
+        Ok(TOUCH_SESSION
+            .key(token_id)
+            .key(session_key(session_id))
+            .arg(now)
+            .invoke_async(&mut conn)
+            .await?)
+    }
+
+    async fn rotate(&self, rotation: TokenRotation) -> Result<Rotation, AppError> {
+        let mut conn = self.conn.clone();
+        let rotated: i32 = ROTATE_SESSION
+            .key(session_key(&rotation.session_id))
+            .key(rotated_tokens_key(&rotation.session_id))
+            .key(user_sessions_key(rotation.user_id))
+            .arg(&rotation.refresh_token_id)
+            .arg(&rotation.new_access_token_id)
+            .arg(&rotation.new_refresh_token_id)
+            .arg(rotation.access_token_max_age)
+            .arg(rotation.refresh_token_max_age)
+            .arg(rotation.now)
+            .invoke_async(&mut conn)
+            .await?;
+
+        Ok(match rotated {
+            1 => Rotation::Rotated,
+            0 => Rotation::Reused,
+            _ => Rotation::Unknown,
+        })
+    }
+
+    async fn revoke(&self, user_id: usize, session_id: &str) -> Result<Option<usize>, AppError> {
+        let mut conn = self.conn.clone();
+        Ok(REVOKE_SESSION
+            .key(session_key(session_id))
+            .key(user_sessions_key(user_id))
+            .arg(session_id)
+            .arg(user_id)
+            .invoke_async(&mut conn)
+            .await?)
+    }
+
+    // the keys of an expired session are gone, `now` isn't needed
+    async fn revoke_all(
+        &self,
+        user_id: usize,
+        except: Option<&str>,
+        _now: usize,
+    ) -> Result<usize, AppError> {
+        let mut conn = self.conn.clone();
+        Ok(REVOKE_USER_SESSIONS
+            .key(user_sessions_key(user_id))
+            .arg(session_key(""))
+            .arg(except.unwrap_or_default())
+            .invoke_async(&mut conn)
+            .await?)
+    }
+
+    // the record of an expired session is gone, `now` isn't needed
+    async fn list(&self, user_id: usize, _now: usize) -> Result<Vec<Session>, AppError> {
+        let mut conn = self.conn.clone();
+
+        let session_ids: Vec<String> = conn.smembers(user_sessions_key(user_id)).await?;
+        if session_ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut pipe = redis::pipe();
+        for session_id in &session_ids {
+            pipe.hgetall(session_key(session_id));
+        }
+        let records: Vec<HashMap<String, String>> = pipe.query_async(&mut conn).await?;
+
+        // the set outlives the expired sessions, they are removed from it here
+        let (active, expired): (Vec<_>, Vec<_>) = session_ids
+            .into_iter()
+            .zip(records)
+            .partition(|(_, record)| !record.is_empty());
+        if !expired.is_empty() {
+            let expired: Vec<String> = expired.into_iter().map(|(id, _)| id).collect();
+            conn.srem::<_, _, ()>(user_sessions_key(user_id), expired)
+                .await?;
+        }
+
+        let mut sessions: Vec<Session> = active
+            .into_iter()
+            .map(|(id, record)| session_from_record(id, record))
+            .collect();
+        sessions.sort_by_key(|session| std::cmp::Reverse(session.created_at));
+        Ok(sessions)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_session_from_record() {
+    let record = HashMap::from([
+        ("user_id".to_string(), "1".to_string()),
+        (
+            "access_token_id".to_string(),
+            "01HSJARKXDAH23Z8SF6ZY475TV".to_string(),
+        ),
+        ("created_at".to_string(), "1711085997".to_string()),
+        ("last_used_at".to_string(), "not a timestamp".to_string()),
+        ("user_agent".to_string(), "curl/8.0".to_string()),
+    ]);
+    let session = session_from_record("01HSJARKXDAH23Z8SF6ZY475TS".to_string(), record);
+
+    assert_eq!(session.id, "01HSJARKXDAH23Z8SF6ZY475TS");
+    assert_eq!(session.created_at.unwrap().timestamp(), 1711085997);
+    assert_eq!(session.last_used_at, None);
+    assert_eq!(session.ip, None);
+    assert_eq!(session.user_agent.as_deref(), Some("curl/8.0"));
+    // the token ids stay private
+    let json = serde_json::to_value(&session).unwrap();
+    assert!(json.get("access_token_id").is_none());
+}
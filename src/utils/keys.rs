@@ -0,0 +1,571 @@
+use base64::{
+    engine::general_purpose::{self, URL_SAFE_NO_PAD},
+    Engine as _,
+};
+use jsonwebtoken::jwk::{
+    AlgorithmParameters, CommonParameters, EllipticCurve, EllipticCurveKeyParameters,
+    EllipticCurveKeyType, Jwk, JwkSet, KeyAlgorithm, OctetKeyPairParameters, OctetKeyPairType,
+    PublicKeyUse, RSAKeyParameters, RSAKeyType,
+};
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey};
+use rsa::pkcs1::DecodeRsaPublicKey;
+use rsa::pkcs8::DecodePublicKey;
+use rsa::traits::PublicKeyParts;
+use rsa::RsaPublicKey;
+use sha2::{Digest, Sha256};
+use spki::der::DecodePem;
+use spki::{ObjectIdentifier, SubjectPublicKeyInfoOwned};
+
+use crate::config::{Config, ConfigError};
+use crate::utils::jwt::TokenType;
+
+// object identifiers of the public key types in SPKI
+const EC_PUBLIC_KEY: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.10045.2.1");
+const P256: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.10045.3.1.7");
+const ED25519: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.101.112");
+
+// HS256 secrets shorter than the hash output are easy to brute force
+const MIN_SECRET_LEN: usize = 32;
+
+/// A key verifying the tokens whose `kid` header is its id.
+#[derive(Clone)]
+pub struct VerificationKey {
+    pub kid: String,
+    pub algorithm: Algorithm,
+    pub decoding: DecodingKey,
+    // the public JWK, HS256 secrets have none as they are never published
+    pub jwk: Option<Jwk>,
+}
+
+/// The keyring of a token type: tokens are signed with the current private key,
+/// and verified by the active key selected by their `kid` header.
+/// Rotated out keys stay active for verification, so rotating keys doesn't log out every session.
+#[derive(Clone)]
+pub struct TokenKeys {
+    // id and algorithm of the current signing key
+    pub kid: String,
+    pub algorithm: Algorithm,
+    pub encoding: EncodingKey,
+    // the key of the current signing key first, then the retired ones
+    pub verification: Vec<VerificationKey>,
+}
+
+impl TokenKeys {
+    /// the key verifying a token with the `kid` header,
+    /// tokens signed before the keys had ids have no `kid` and are verified by the current key
+    pub fn verification_key(&self, kid: Option<&str>) -> Option<&VerificationKey> {
+        let kid = kid.unwrap_or(&self.kid);
+        self.verification.iter().find(|key| key.kid == kid)
+    }
+}
+
+impl VerificationKey {
+    /// parse a PEM public key, RSA (SPKI or PKCS#1), P-256 or Ed25519.
+    /// The algorithm is the one of the key type: RS256, ES256 or EdDSA.
+    pub fn from_pem(public_key: &[u8]) -> Result<Self, String> {
+        let pem = std::str::from_utf8(public_key).map_err(|e| e.to_string())?;
+        if let Ok(key) =
+            RsaPublicKey::from_public_key_pem(pem).or_else(|_| RsaPublicKey::from_pkcs1_pem(pem))
+        {
+            let n = URL_SAFE_NO_PAD.encode(key.n().to_bytes_be());
+            let e = URL_SAFE_NO_PAD.encode(key.e().to_bytes_be());
+            let decoding = DecodingKey::from_rsa_components(&n, &e).map_err(|e| e.to_string())?;
+            return Ok(Self::public(
+                Algorithm::RS256,
+                decoding,
+                AlgorithmParameters::RSA(RSAKeyParameters {
+                    key_type: RSAKeyType::RSA,
+                    n,
+                    e,
+                }),
+            ));
+        }
+
+        let spki = SubjectPublicKeyInfoOwned::from_pem(pem)
+            .map_err(|e| format!("not a PEM public key: {}", e))?;
+        let key = spki.subject_public_key.raw_bytes();
+        match spki.algorithm.oid {
+            EC_PUBLIC_KEY => {
+                let curve = spki
+                    .algorithm
+                    .parameters
+                    .as_ref()
+                    .and_then(|params| params.decode_as::<ObjectIdentifier>().ok());
+                // the point is uncompressed, 0x04 followed by the coordinates
+                if curve != Some(P256) || key.len() != 65 || key[0] != 0x04 {
+                    return Err("only P-256 EC keys are supported".to_string());
+                }
+                let x = URL_SAFE_NO_PAD.encode(&key[1..33]);
+                let y = URL_SAFE_NO_PAD.encode(&key[33..]);
+                let decoding =
+                    DecodingKey::from_ec_components(&x, &y).map_err(|e| e.to_string())?;
+                Ok(Self::public(
+                    Algorithm::ES256,
+                    decoding,
+                    AlgorithmParameters::EllipticCurve(EllipticCurveKeyParameters {
+                        key_type: EllipticCurveKeyType::EC,
+                        curve: EllipticCurve::P256,
+                        x,
+                        y,
+                    }),
+                ))
+            }
+            ED25519 => {
+                let x = URL_SAFE_NO_PAD.encode(key);
+                let decoding = DecodingKey::from_ed_components(&x).map_err(|e| e.to_string())?;
+                Ok(Self::public(
+                    Algorithm::EdDSA,
+                    decoding,
+                    AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+                        key_type: OctetKeyPairType::OctetKeyPair,
+                        curve: EllipticCurve::Ed25519,
+                        x,
+                    }),
+                ))
+            }
+            oid => Err(format!("unsupported key type {}", oid)),
+        }
+    }
+
+    /// a HS256 secret, it verifies the tokens it signed
+    pub fn from_secret(secret: &[u8]) -> Result<Self, String> {
+        if secret.len() < MIN_SECRET_LEN {
+            return Err(format!(
+                "HS256 secrets must be at least {} bytes",
+                MIN_SECRET_LEN
+            ));
+        }
+        let k = URL_SAFE_NO_PAD.encode(secret);
+        Ok(VerificationKey {
+            kid: thumbprint(&format!(r#"{{"k":"{}","kty":"oct"}}"#, k)),
+            algorithm: Algorithm::HS256,
+            decoding: DecodingKey::from_secret(secret),
+            jwk: None,
+        })
+    }
+
+    fn public(algorithm: Algorithm, decoding: DecodingKey, params: AlgorithmParameters) -> Self {
+        // the members required by RFC 7638, in lexicographic order
+        let members = match &params {
+            AlgorithmParameters::RSA(key) => {
+                format!(r#"{{"e":"{}","kty":"RSA","n":"{}"}}"#, key.e, key.n)
+            }
+            AlgorithmParameters::EllipticCurve(key) => format!(
+                r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#,
+                key.x, key.y
+            ),
+            AlgorithmParameters::OctetKeyPair(key) => {
+                format!(r#"{{"crv":"Ed25519","kty":"OKP","x":"{}"}}"#, key.x)
+            }
+            AlgorithmParameters::OctetKey(_) => unreachable!("secrets aren't public keys"),
+        };
+        let kid = thumbprint(&members);
+        let key_algorithm = match algorithm {
+            Algorithm::ES256 => KeyAlgorithm::ES256,
+            Algorithm::EdDSA => KeyAlgorithm::EdDSA,
+            _ => KeyAlgorithm::RS256,
+        };
+        let jwk = Jwk {
+            common: CommonParameters {
+                public_key_use: Some(PublicKeyUse::Signature),
+                key_algorithm: Some(key_algorithm),
+                key_id: Some(kid.clone()),
+                ..Default::default()
+            },
+            algorithm: params,
+        };
+
+        VerificationKey {
+            kid,
+            algorithm,
+            decoding,
+            jwk: Some(jwk),
+        }
+    }
+}
+
+// the key id is the JWK thumbprint (RFC 7638), it's stable as long as the key is the same
+fn thumbprint(members: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(members))
+}
+
+/// The JWT keys, parsed once at startup and held by `AppState`,
+/// so the base64 PEMs aren't decoded and parsed again for every token.
+#[derive(Clone)]
+pub struct KeyStore {
+    access: TokenKeys,
+    refresh: TokenKeys,
+}
+
+impl KeyStore {
+    /// parse the base64 encoded keys of the configuration for the algorithm of each token type,
+    /// all the invalid keys are reported together
+    pub fn from_config(config: &Config) -> Result<Self, ConfigError> {
+        let mut errors = Vec::new();
+        let access = load(
+            &mut errors,
+            ("ACCESS_TOKEN_ALGORITHM", config.access_token_algorithm),
+            ("ACCESS_TOKEN_PRIVATE_KEY", &config.access_token_private_key),
+            ("ACCESS_TOKEN_PUBLIC_KEY", &config.access_token_public_key),
+            (
+                "ACCESS_TOKEN_RETIRED_PUBLIC_KEYS",
+                &config.access_token_retired_public_keys,
+            ),
+        );
+        let refresh = load(
+            &mut errors,
+            ("REFRESH_TOKEN_ALGORITHM", config.refresh_token_algorithm),
+            (
+                "REFRESH_TOKEN_PRIVATE_KEY",
+                &config.refresh_token_private_key,
+            ),
+            ("REFRESH_TOKEN_PUBLIC_KEY", &config.refresh_token_public_key),
+            (
+                "REFRESH_TOKEN_RETIRED_PUBLIC_KEYS",
+                &config.refresh_token_retired_public_keys,
+            ),
+        );
+
+        match (access, refresh) {
+            (Some(access), Some(refresh)) => Ok(Self { access, refresh }),
+            _ => Err(ConfigError(errors)),
+        }
+    }
+
+    pub fn keys(&self, kind: TokenType) -> &TokenKeys {
+        match kind {
+            TokenType::AccessToken => &self.access,
+            TokenType::RefreshToken => &self.refresh,
+        }
+    }
+
+    /// the public keys verifying access tokens, published so other services can verify them.
+    /// Refresh tokens are only verified by this server, their keys aren't published.
+    pub fn jwks(&self) -> JwkSet {
+        JwkSet {
+            keys: self
+                .access
+                .verification
+                .iter()
+                .filter_map(|key| key.jwk.clone())
+                .collect(),
+        }
+    }
+
+    /// the keys in `tests/fixtures`, only used by tests
+    #[cfg(test)]
+    pub fn test_keys() -> Self {
+        Self::from_config(&crate::config::test_config()).unwrap()
+    }
+}
+
+// load the keyring of a token type, the settings are given with their names to report the errors
+fn load(
+    errors: &mut Vec<String>,
+    algorithm: (&str, Algorithm),
+    private_key: (&str, &str),
+    public_key: (&str, &str),
+    retired_public_keys: (&str, &[String]),
+) -> Option<TokenKeys> {
+    let (name, algorithm) = algorithm;
+    if !matches!(
+        algorithm,
+        Algorithm::RS256 | Algorithm::ES256 | Algorithm::EdDSA | Algorithm::HS256
+    ) {
+        errors.push(format!(
+            "{} is invalid: {:?} isn't supported, use RS256, ES256, EdDSA or HS256",
+            name, algorithm
+        ));
+        return None;
+    }
+
+    let private_pem = check(errors, private_key.0, decode_pem(private_key.1))?;
+    let encoding = check(
+        errors,
+        private_key.0,
+        match algorithm {
+            Algorithm::ES256 => EncodingKey::from_ec_pem(&private_pem),
+            Algorithm::EdDSA => EncodingKey::from_ed_pem(&private_pem),
+            Algorithm::HS256 => Ok(EncodingKey::from_secret(&private_pem)),
+            _ => EncodingKey::from_rsa_pem(&private_pem),
+        }
+        .map_err(|e| e.to_string()),
+    );
+
+    // a HS256 secret verifies the tokens it signed, other algorithms need the public key
+    let current = if algorithm == Algorithm::HS256 {
+        check(
+            errors,
+            private_key.0,
+            VerificationKey::from_secret(&private_pem),
+        )
+    } else if public_key.1.trim().is_empty() {
+        errors.push(format!("{} must be set", public_key.0));
+        None
+    } else {
+        let key = decode_pem(public_key.1).and_then(|pem| VerificationKey::from_pem(&pem));
+        let key = key.and_then(|key| match key.algorithm == algorithm {
+            true => Ok(key),
+            false => Err(format!(
+                "it's a {:?} key, not a {:?} key",
+                key.algorithm, algorithm
+            )),
+        });
+        check(errors, public_key.0, key)
+    };
+
+    // the retired public keys may be of another public key algorithm, so it can be changed like a key.
+    // With HS256 they are secrets, a change to or from HS256 can't keep the old tokens
+    let mut verification = vec![current];
+    for (i, key) in retired_public_keys.1.iter().enumerate() {
+        let key = decode_pem(key).and_then(|pem| match algorithm {
+            Algorithm::HS256 => VerificationKey::from_secret(&pem),
+            _ => VerificationKey::from_pem(&pem),
+        });
+        verification.push(check(
+            errors,
+            &format!("{}[{}]", retired_public_keys.0, i),
+            key,
+        ));
+    }
+
+    let (encoding, verification) = (
+        encoding?,
+        verification.into_iter().collect::<Option<Vec<_>>>()?,
+    );
+    let keys = TokenKeys {
+        kid: verification[0].kid.clone(),
+        algorithm,
+        encoding,
+        verification,
+    };
+    // sign and verify a token, so a private key that doesn't match its public key is found at startup
+    check(
+        errors,
+        private_key.0,
+        probe(&keys)
+            .map_err(|e| format!("it doesn't sign tokens verified by {}: {}", public_key.0, e)),
+    )?;
+    Some(keys)
+}
+
+fn probe(keys: &TokenKeys) -> Result<(), jsonwebtoken::errors::Error> {
+    let header = jsonwebtoken::Header::new(keys.algorithm);
+    let token = jsonwebtoken::encode(&header, &serde_json::json!({}), &keys.encoding)?;
+    let mut validation = jsonwebtoken::Validation::new(keys.algorithm);
+    validation.set_required_spec_claims::<&str>(&[]);
+    validation.validate_exp = false;
+    jsonwebtoken::decode::<serde_json::Value>(&token, &keys.verification[0].decoding, &validation)?;
+    Ok(())
+}
+
+// record the error of an invalid key with the name of its setting
+fn check<T>(errors: &mut Vec<String>, name: &str, key: Result<T, String>) -> Option<T> {
+    key.map_err(|e| errors.push(format!("{} is invalid: {}", name, e)))
+        .ok()
+}
+
+// the keys are configured as base64 encoded PEM, or base64 encoded secrets for HS256
+fn decode_pem(key: &str) -> Result<Vec<u8>, String> {
+    general_purpose::STANDARD
+        .decode(key.trim())
+        .map_err(|e| format!("not base64 encoded: {}", e))
+}
+
+#[cfg(test)]
+#[test]
+fn test_invalid_keys() {
+    let public_key = general_purpose::STANDARD.encode(include_str!(
+        "../../tests/fixtures/access_token_public_key.pem"
+    ));
+    let config = Config {
+        access_token_private_key: "not base64!".to_string(),
+        access_token_public_key: public_key.clone(),
+        refresh_token_private_key: general_purpose::STANDARD.encode("not a pem"),
+        refresh_token_public_key: public_key,
+        ..crate::config::test_config()
+    };
+
+    let errors = KeyStore::from_config(&config).err().unwrap().0;
+    assert_eq!(errors.len(), 2);
+    assert!(errors[0].starts_with("ACCESS_TOKEN_PRIVATE_KEY is invalid: not base64 encoded"));
+    assert!(errors[1].starts_with("REFRESH_TOKEN_PRIVATE_KEY is invalid"));
+}
+
+// a key in `tests/fixtures`, base64 encoded like in the configuration
+#[cfg(test)]
+fn fixture(name: &str) -> String {
+    let path = format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name);
+    general_purpose::STANDARD.encode(std::fs::read(path).unwrap())
+}
+
+// sign an access token with the keys and decode it with the other keys
+#[cfg(test)]
+fn sign_and_decode(
+    signing_keys: &KeyStore,
+    verifying_keys: &KeyStore,
+) -> (
+    String,
+    Result<crate::utils::jwt::Claims, jsonwebtoken::errors::Error>,
+) {
+    use crate::utils::jwt::{decode_token, generate_token, Claims, SystemClock};
+
+    let config = crate::config::test_config();
+    let claims = Claims::new(
+        &config,
+        TokenType::AccessToken,
+        1,
+        crate::models::user::Role::User,
+        "01HSJARKXDAH23Z8SF6ZY475TS",
+        &SystemClock,
+    );
+    let token = generate_token(signing_keys, TokenType::AccessToken, &claims).unwrap();
+    let claims = decode_token(&config, verifying_keys, TokenType::AccessToken, &token);
+    (token, claims)
+}
+
+#[test]
+fn test_key_rotation() {
+    let keyring = |private_key: &str, public_key: &str, retired: &[&str]| {
+        KeyStore::from_config(&Config {
+            access_token_private_key: fixture(private_key),
+            access_token_public_key: fixture(public_key),
+            access_token_retired_public_keys: retired.iter().map(|key| fixture(key)).collect(),
+            ..crate::config::test_config()
+        })
+        .unwrap()
+    };
+    let old_keys = keyring(
+        "refresh_token_private_key.pem",
+        "refresh_token_public_key.pem",
+        &[],
+    );
+    let new_keys = keyring(
+        "access_token_private_key.pem",
+        "access_token_public_key.pem",
+        &["refresh_token_public_key.pem"],
+    );
+
+    let (old_token, _) = sign_and_decode(&old_keys, &old_keys);
+    let kid = |token: &str| jsonwebtoken::decode_header(token).unwrap().kid.unwrap();
+    assert_eq!(kid(&old_token), old_keys.access.kid);
+    assert_ne!(old_keys.access.kid, new_keys.access.kid);
+
+    // tokens signed by the retired key are still accepted after the rotation
+    assert!(sign_and_decode(&old_keys, &new_keys).1.is_ok());
+    assert!(sign_and_decode(&new_keys, &new_keys).1.is_ok());
+    // but not once the retired key is removed
+    let rotated_keys = keyring(
+        "access_token_private_key.pem",
+        "access_token_public_key.pem",
+        &[],
+    );
+    assert!(sign_and_decode(&old_keys, &rotated_keys).1.is_err());
+
+    let jwks = serde_json::to_value(new_keys.jwks()).unwrap();
+    let kids = jwks["keys"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|key| key["kid"].as_str().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        kids,
+        [new_keys.access.kid.as_str(), old_keys.access.kid.as_str()]
+    );
+    assert_eq!(jwks["keys"][0]["kty"], "RSA");
+    assert_eq!(jwks["keys"][0]["alg"], "RS256");
+    assert_eq!(jwks["keys"][0]["e"], "AQAB");
+}
+
+#[test]
+fn test_algorithms() {
+    let keyring = |algorithm: Algorithm, private_key: String, public_key: String| {
+        KeyStore::from_config(&Config {
+            access_token_algorithm: algorithm,
+            access_token_private_key: private_key,
+            access_token_public_key: public_key,
+            // tokens signed by the RS256 fixture key are still accepted
+            access_token_retired_public_keys: vec![fixture("access_token_public_key.pem")],
+            ..crate::config::test_config()
+        })
+    };
+    let rs256_keys = KeyStore::test_keys();
+    let es256_keys = keyring(
+        Algorithm::ES256,
+        fixture("es256_private_key.pem"),
+        fixture("es256_public_key.pem"),
+    )
+    .unwrap();
+    let eddsa_keys = keyring(
+        Algorithm::EdDSA,
+        fixture("ed25519_private_key.pem"),
+        fixture("ed25519_public_key.pem"),
+    )
+    .unwrap();
+
+    for (keys, alg, kty) in [(&es256_keys, "ES256", "EC"), (&eddsa_keys, "EdDSA", "OKP")] {
+        let (token, claims) = sign_and_decode(keys, keys);
+        assert!(claims.is_ok());
+        assert_eq!(
+            jsonwebtoken::decode_header(&token).unwrap().alg,
+            keys.access.algorithm
+        );
+        // the RS256 token of the retired key is verified with its own algorithm
+        assert!(sign_and_decode(&rs256_keys, keys).1.is_ok());
+        // tokens signed by one algorithm can't be verified as another one
+        assert!(sign_and_decode(keys, &rs256_keys).1.is_err());
+
+        let jwks = serde_json::to_value(keys.jwks()).unwrap();
+        assert_eq!(jwks["keys"][0]["alg"], alg);
+        assert_eq!(jwks["keys"][0]["kty"], kty);
+        assert_eq!(jwks["keys"][1]["alg"], "RS256");
+    }
+
+    // the secret isn't published, and it verifies the tokens it signed
+    let hs256_keys = KeyStore::from_config(&Config {
+        access_token_algorithm: Algorithm::HS256,
+        access_token_private_key: general_purpose::STANDARD
+            .encode("a secret only used by the tests.."),
+        access_token_public_key: String::new(),
+        ..crate::config::test_config()
+    })
+    .unwrap();
+    assert!(sign_and_decode(&hs256_keys, &hs256_keys).1.is_ok());
+    assert!(hs256_keys.jwks().keys.is_empty());
+
+    let errors = keyring(
+        Algorithm::HS256,
+        general_purpose::STANDARD.encode("too short"),
+        String::new(),
+    )
+    .err()
+    .unwrap()
+    .0;
+    assert!(errors[0].contains("at least 32 bytes"));
+
+    // the public key must be of the algorithm and match the private key
+    let errors = keyring(
+        Algorithm::ES256,
+        fixture("es256_private_key.pem"),
+        fixture("ed25519_public_key.pem"),
+    )
+    .err()
+    .unwrap()
+    .0;
+    assert!(errors[0].starts_with("ACCESS_TOKEN_PUBLIC_KEY is invalid: it's a EdDSA key"));
+    let errors = keyring(
+        Algorithm::RS256,
+        fixture("access_token_private_key.pem"),
+        fixture("refresh_token_public_key.pem"),
+    )
+    .err()
+    .unwrap()
+    .0;
+    assert!(errors[0].starts_with("ACCESS_TOKEN_PRIVATE_KEY is invalid: it doesn't sign tokens"));
+    let errors = keyring(Algorithm::PS256, String::new(), String::new())
+        .err()
+        .unwrap()
+        .0;
+    assert!(errors[0].starts_with("ACCESS_TOKEN_ALGORITHM is invalid: PS256 isn't supported"));
+}
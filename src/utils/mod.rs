@@ -1 +1,4 @@
 pub mod jwt;
+pub mod keys;
+pub mod one_time_token;
+pub mod totp;
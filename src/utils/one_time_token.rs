@@ -0,0 +1,36 @@
+use base64::{engine::general_purpose, Engine as _};
+use rand_core::{OsRng, RngCore};
+use sha2::{Digest, Sha256};
+
+// The random tokens sent by email, e.g. to reset a password, and the recovery codes of MFA.
+// Only their hashes are stored, so they can't be read from the database. They are random,
+// a fast hash is enough.
+
+/// a new random token, 256 bits encoded as url safe base64
+pub fn generate() -> String {
+    let mut token = [0u8; 32];
+    OsRng.fill_bytes(&mut token);
+    general_purpose::URL_SAFE_NO_PAD.encode(token)
+}
+
+/// the hash of a token as it's stored, sha256 as hex
+pub fn hash(token: &str) -> String {
+    Sha256::digest(token.trim().as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[cfg(test)]
+#[test]
+fn test_one_time_token() {
+    let token = generate();
+    assert_eq!(token.len(), 43);
+    assert_ne!(token, generate());
+    assert_eq!(hash(&token).len(), 64);
+    assert_eq!(hash(&token), hash(&format!(" {}\n", token)));
+    assert_eq!(
+        hash("abc"),
+        "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+    );
+}
@@ -0,0 +1,143 @@
+use base32::Alphabet;
+use hmac::{Hmac, Mac};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use rand_core::{OsRng, RngCore};
+use sha1::Sha1;
+use subtle::ConstantTimeEq;
+
+use crate::utils::one_time_token;
+
+// Time-based one-time passwords (RFC 6238) with the defaults of the authenticator apps:
+// HMAC-SHA1, 6 digits and 30 second steps. The secrets are base32 encoded, as in the provisioning uri.
+
+// the seconds a code is valid
+const STEP: u64 = 30;
+const DIGITS: u32 = 6;
+// the codes of the previous and next steps are accepted too, the clock of the phone may be off
+const SKEW: u64 = 1;
+// 160 bits, the size recommended for HMAC-SHA1 by RFC 4226
+const SECRET_LEN: usize = 20;
+const RECOVERY_CODES: usize = 10;
+
+// base32 without padding (RFC 4648), as in the provisioning uri
+const BASE32: Alphabet = Alphabet::Rfc4648 { padding: false };
+
+// decode a secret, case-insensitive and ignoring padding, `None` if it's not base32
+fn decode_secret(secret: &str) -> Option<Vec<u8>> {
+    base32::decode(BASE32, &secret.trim_end_matches('=').to_ascii_uppercase())
+}
+
+// the HOTP code of a counter (RFC 4226)
+fn hotp(secret: &[u8], counter: u64, digits: u32) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("hmac accepts keys of any size");
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[19] & 0xf) as usize;
+    let code = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+    code % 10u32.pow(digits)
+}
+
+/// a new random secret, base32 encoded
+pub fn generate_secret() -> String {
+    let mut secret = [0u8; SECRET_LEN];
+    OsRng.fill_bytes(&mut secret);
+    base32::encode(BASE32, &secret)
+}
+
+/// the `otpauth://` uri of a secret, authenticator apps scan it from a QR code
+pub fn provisioning_uri(issuer: &str, account: &str, secret: &str) -> String {
+    let issuer = utf8_percent_encode(issuer, NON_ALPHANUMERIC).to_string();
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        issuer,
+        utf8_percent_encode(account, NON_ALPHANUMERIC),
+        secret,
+        issuer,
+        DIGITS,
+        STEP
+    )
+}
+
+/// check a code of the secret at the given unix time,
+/// returns the time step it belongs to, so the code can't be used twice
+pub fn verify(secret: &str, code: &str, now: u64) -> Option<u64> {
+    let secret = decode_secret(secret)?;
+    let code = code.trim();
+    if code.len() != DIGITS as usize || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let code: u32 = code.parse().ok()?;
+
+    let step = now / STEP;
+    // compared in constant time, the timing mustn't tell how close a guess is
+    (step.saturating_sub(SKEW)..=step + SKEW)
+        .find(|&step| hotp(&secret, step, DIGITS).ct_eq(&code).into())
+}
+
+/// new recovery codes, e.g. `k3x9p-2mwqa`, each of them logs in once instead of a TOTP code
+pub fn generate_recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODES)
+        .map(|_| {
+            let mut bytes = [0u8; 7];
+            OsRng.fill_bytes(&mut bytes);
+            let code = base32::encode(BASE32, &bytes)[..10].to_lowercase();
+            format!("{}-{}", &code[..5], &code[5..])
+        })
+        .collect()
+}
+
+/// the hash of a recovery code as it's stored, whatever its case and separators
+pub fn hash_recovery_code(code: &str) -> String {
+    let code: String = code
+        .chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    one_time_token::hash(&code)
+}
+
+#[cfg(test)]
+#[test]
+fn test_totp() {
+    // the SHA1 test vectors of RFC 6238, truncated to 6 digits
+    let secret = base32::encode(BASE32, b"12345678901234567890");
+    assert_eq!(secret, "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ");
+    assert_eq!(
+        decode_secret(&format!("{}==", secret.to_lowercase())).unwrap(),
+        b"12345678901234567890"
+    );
+    for (time, code) in [
+        (59, "287082"),
+        (1111111109, "081804"),
+        (1234567890, "005924"),
+        (2000000000, "279037"),
+    ] {
+        assert_eq!(verify(&secret, code, time), Some(time / STEP));
+    }
+
+    // a code is valid for a step before and after its own
+    assert_eq!(verify(&secret, "081804", 1111111109 + 30), Some(37037036));
+    assert_eq!(verify(&secret, "081804", 1111111109 + 60), None);
+    assert_eq!(verify(&secret, "81804", 1111111109), None);
+    assert_eq!(verify(&secret, "08180a", 1111111109), None);
+    assert_eq!(verify("not base32!", "081804", 1111111109), None);
+
+    assert_eq!(generate_secret().len(), 32);
+    assert_eq!(
+        provisioning_uri("pwr.ink", "elton@pwr.ink", "GEZDGNBV"),
+        "otpauth://totp/pwr%2Eink:elton%40pwr%2Eink?secret=GEZDGNBV&issuer=pwr%2Eink&algorithm=SHA1&digits=6&period=30"
+    );
+
+    let codes = generate_recovery_codes();
+    assert_eq!(codes.len(), 10);
+    assert_eq!(codes[0].len(), 11);
+    assert_eq!(
+        hash_recovery_code(&codes[0]),
+        hash_recovery_code(&codes[0].replace('-', "").to_uppercase())
+    );
+}